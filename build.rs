@@ -48,7 +48,7 @@ fn compile_lwip() {
         .file("src/lwip/core/netif.c")
         .file("src/lwip/core/pbuf.c")
         .file("src/lwip/core/raw.c")
-        // .file("src/lwip/core/stats.c")
+        .file("src/lwip/core/stats.c")
         // .file("src/lwip/core/sys.c")
         .file("src/lwip/core/tcp.c")
         .file("src/lwip/core/tcp_in.c")
@@ -73,6 +73,7 @@ fn compile_lwip() {
         // .file("src/lwip/core/ipv6/mld6.c")
         .file("src/lwip/core/ipv6/nd6.c")
         .file("src/lwip/custom/sys_arch.c")
+        .file("src/lwip/custom/netstack_stats.c")
         .include("src/lwip/custom")
         .include("src/lwip/include")
         .warnings(false)
@@ -100,7 +101,7 @@ fn generate_lwip_bindings() {
         .clang_arg("-I./src/lwip/custom")
         .clang_arg("-Wno-everything")
         .layout_tests(false)
-        .parse_callbacks(Box::new(bindgen::CargoCallbacks));
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()));
     if arch == "aarch64" && os == "ios" {
         // https://github.com/rust-lang/rust-bindgen/issues/1211
         builder = builder.clang_arg("--target=arm64-apple-ios");
@@ -0,0 +1,11 @@
+//! IPv4 and IPv6 fragments of a UDP datagram, overlapping, out of order or
+//! incomplete.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use netstack_lwip_fuzz::{run_fragments, Fragments};
+
+fuzz_target!(|input: Fragments| {
+    run_fragments(input);
+});
@@ -0,0 +1,11 @@
+//! Raw bytes written into the stack, as any app on the device could.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use netstack_lwip_fuzz::Harness;
+
+fuzz_target!(|data: &[u8]| {
+    let mut harness = Harness::new();
+    harness.input(data);
+});
@@ -0,0 +1,11 @@
+//! Sequences of segments, reads, writes and timeouts driving the TCP state
+//! machine of a few connections.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use netstack_lwip_fuzz::{run_tcp, TcpAction};
+
+fuzz_target!(|actions: Vec<TcpAction>| {
+    run_tcp(actions);
+});
@@ -0,0 +1,305 @@
+//! Structured inputs for the fuzz targets, and the harness running them
+//! against a `SyncNetStack`.
+//!
+//! Every input runs on a fresh stack. Time only moves when an input says
+//! so, on a `VirtualClock`, which keeps runs reproducible.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use bytes::Bytes;
+use netstack_lwip::test_util::{Packet, TcpSegment, UdpDatagram, ACK, SYN};
+use netstack_lwip::{NetStack, SyncNetStack, TcpHandle, VirtualClock};
+
+/// Longest payload generated, above the MTU so that oversized packets and
+/// reassembly limits are covered too.
+const MAX_PAYLOAD: usize = 3000;
+
+fn clock() -> &'static VirtualClock {
+    static CLOCK: OnceLock<VirtualClock> = OnceLock::new();
+    CLOCK.get_or_init(|| {
+        let clock = VirtualClock::new();
+        NetStack::set_clock(clock.clone());
+        clock
+    })
+}
+
+pub struct Harness {
+    pub stack: SyncNetStack,
+    pub handles: Vec<TcpHandle>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        clock();
+        Harness {
+            stack: NetStack::builder().build_sync().unwrap(),
+            handles: Vec::new(),
+        }
+    }
+
+    /// Feeds a packet, then takes what it made the stack emit.
+    pub fn input(&mut self, pkt: &[u8]) -> Vec<Packet> {
+        let _ = self.stack.input(pkt);
+        while let Some(handle) = self.stack.accept() {
+            self.handles.push(handle);
+        }
+        while self.stack.recv_udp().is_some() {}
+        self.output()
+    }
+
+    pub fn output(&mut self) -> Vec<Packet> {
+        std::iter::from_fn(|| self.stack.poll_output())
+            .map(Packet::parse)
+            .collect()
+    }
+
+    pub fn advance(&mut self, elapsed: Duration) -> Vec<Packet> {
+        self.stack.advance_time(elapsed);
+        self.output()
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.handles.clear();
+        // Lets lingering connections and reassembly buffers expire, so that
+        // they do not pile up across inputs.
+        self.advance(Duration::from_secs(300));
+    }
+}
+
+/// One of a few flows, so that inputs can interleave connections.
+#[derive(Arbitrary, Debug, Clone, Copy)]
+pub struct Flow {
+    ipv6: bool,
+    port: u8,
+}
+
+impl Flow {
+    fn addrs(self) -> (SocketAddr, SocketAddr) {
+        let port = 1000 + (self.port % 4) as u16;
+        if self.ipv6 {
+            (
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), port),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), 443),
+            )
+        } else {
+            (
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 443),
+            )
+        }
+    }
+}
+
+/// A step of the TCP state machine target.
+#[derive(Arbitrary, Debug)]
+pub enum TcpAction {
+    /// A segment from the peer. Sequence numbers are offsets from what the
+    /// peer sent and received so far, mostly in window.
+    Segment {
+        flow: Flow,
+        flags: u8,
+        seq_offset: i16,
+        ack_offset: i16,
+        window: u16,
+        options: Vec<u8>,
+        payload_len: u16,
+    },
+    /// Reads from an accepted connection.
+    Read { handle: u8, len: u16 },
+    /// Writes to an accepted connection.
+    Write { handle: u8, len: u16 },
+    Shutdown { handle: u8 },
+    Drop { handle: u8 },
+    AdvanceTime { millis: u16 },
+}
+
+/// What the peer knows of a flow.
+#[derive(Default, Clone, Copy)]
+struct PeerState {
+    snd_nxt: u32,
+    rcv_nxt: u32,
+}
+
+/// Runs TCP actions, tracking the sequence numbers of every flow from the
+/// segments the stack sends.
+pub fn run_tcp(actions: Vec<TcpAction>) {
+    let mut harness = Harness::new();
+    let mut peers = [[PeerState::default(); 4]; 2];
+    let mut buf = vec![0u8; 1 << 16];
+    for action in actions {
+        let out = match action {
+            TcpAction::Segment {
+                flow,
+                flags,
+                seq_offset,
+                ack_offset,
+                window,
+                mut options,
+                payload_len,
+            } => {
+                let peer = &mut peers[flow.ipv6 as usize][(flow.port % 4) as usize];
+                let (src, dst) = flow.addrs();
+                options.truncate(40);
+                options.resize(options.len().next_multiple_of(4), 0);
+                let seg = TcpSegment {
+                    src,
+                    dst,
+                    seq: peer.snd_nxt.wrapping_add(seq_offset as u32),
+                    ack: peer.rcv_nxt.wrapping_add(ack_offset as u32),
+                    flags,
+                    window,
+                    options,
+                    payload: Bytes::from(vec![0x61; payload_len as usize % MAX_PAYLOAD]),
+                };
+                if seq_offset == 0 {
+                    peer.snd_nxt = peer.snd_nxt.wrapping_add(seg.len());
+                }
+                harness.input(&seg.to_packet())
+            }
+            TcpAction::Read { handle, len } => {
+                if let Some(h) = pick(&mut harness.handles, handle) {
+                    let _ = h.read(&mut buf[..len as usize]);
+                }
+                harness.output()
+            }
+            TcpAction::Write { handle, len } => {
+                if let Some(h) = pick(&mut harness.handles, handle) {
+                    let _ = h.write(&buf[..len as usize]);
+                }
+                harness.output()
+            }
+            TcpAction::Shutdown { handle } => {
+                if let Some(h) = pick(&mut harness.handles, handle) {
+                    let _ = h.shutdown();
+                }
+                harness.output()
+            }
+            TcpAction::Drop { handle } => {
+                if !harness.handles.is_empty() {
+                    let i = handle as usize % harness.handles.len();
+                    harness.handles.swap_remove(i);
+                }
+                harness.output()
+            }
+            TcpAction::AdvanceTime { millis } => {
+                harness.advance(Duration::from_millis(millis as u64))
+            }
+        };
+        for pkt in out {
+            if let Packet::Tcp(seg) = pkt {
+                track(&mut peers, &seg);
+            }
+        }
+    }
+}
+
+fn pick(handles: &mut [TcpHandle], i: u8) -> Option<&mut TcpHandle> {
+    let len = handles.len();
+    (len > 0).then(|| &mut handles[i as usize % len])
+}
+
+/// Follows a segment sent by the stack toward a flow's peer.
+fn track(peers: &mut [[PeerState; 4]; 2], seg: &TcpSegment) {
+    let port = seg.dst.port().wrapping_sub(1000);
+    if port >= 4 {
+        return;
+    }
+    let peer = &mut peers[seg.dst.is_ipv6() as usize][port as usize];
+    if seg.has(SYN | ACK) {
+        peer.rcv_nxt = seg.seq.wrapping_add(1);
+        peer.snd_nxt = seg.ack;
+    } else {
+        let end = seg.seq.wrapping_add(seg.len());
+        if end.wrapping_sub(peer.rcv_nxt) as i32 > 0 {
+            peer.rcv_nxt = end;
+        }
+    }
+}
+
+/// A piece of a UDP datagram sent as an IP fragment.
+#[derive(Arbitrary, Debug)]
+pub struct Fragment {
+    /// Offset in 8-byte units, possibly past the end or overlapping others.
+    offset: u16,
+    len: u16,
+    more: bool,
+    /// Time passing before the fragment arrives.
+    delay_millis: u8,
+}
+
+/// Fragments of a UDP datagram, in any order, overlapping or with holes.
+#[derive(Arbitrary, Debug)]
+pub struct Fragments {
+    flow: Flow,
+    id: u16,
+    payload_len: u16,
+    fragments: Vec<Fragment>,
+}
+
+pub fn run_fragments(input: Fragments) {
+    let mut harness = Harness::new();
+    let (src, dst) = input.flow.addrs();
+    let datagram = UdpDatagram {
+        src,
+        dst,
+        payload: Bytes::from(vec![0x62; input.payload_len as usize % MAX_PAYLOAD]),
+    };
+    let pkt = datagram.to_packet();
+    let hlen = if src.is_ipv6() { 40 } else { 20 };
+    let l4 = &pkt[hlen..];
+    for frag in input.fragments.iter().take(64) {
+        if frag.delay_millis > 0 {
+            harness.advance(Duration::from_millis(frag.delay_millis as u64));
+        }
+        let start = (frag.offset as usize * 8).min(l4.len());
+        let end = (start + frag.len as usize).min(l4.len());
+        harness.input(&fragment(&pkt[..hlen], input.id, frag, &l4[start..end]));
+    }
+}
+
+/// Builds an IPv4 fragment, or an IPv6 one with a fragment header, from the
+/// header of the unfragmented packet.
+fn fragment(hdr: &[u8], id: u16, frag: &Fragment, data: &[u8]) -> Vec<u8> {
+    let off_flags = (frag.offset & 0x1fff) << 3 | frag.more as u16;
+    let mut pkt = hdr.to_vec();
+    if hdr[0] >> 4 == 4 {
+        pkt[2..4].copy_from_slice(&((20 + data.len()) as u16).to_be_bytes());
+        pkt[4..6].copy_from_slice(&id.to_be_bytes());
+        let v4_flags = (frag.offset & 0x1fff) | (frag.more as u16) << 13;
+        pkt[6..8].copy_from_slice(&v4_flags.to_be_bytes());
+        pkt[10..12].copy_from_slice(&[0, 0]);
+        let cksum = !ones_sum(&pkt);
+        pkt[10..12].copy_from_slice(&cksum.to_be_bytes());
+    } else {
+        pkt[4..6].copy_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        let next = pkt[6];
+        pkt[6] = 44;
+        pkt.extend_from_slice(&[next, 0]);
+        pkt.extend_from_slice(&off_flags.to_be_bytes());
+        pkt.extend_from_slice(&(id as u32).to_be_bytes());
+    }
+    pkt.extend_from_slice(data);
+    pkt
+}
+
+fn ones_sum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
@@ -143,22 +143,24 @@ pub const TARGET_RT_LITTLE_ENDIAN: u32 = 1;
 pub const TARGET_RT_BIG_ENDIAN: u32 = 0;
 pub const TARGET_RT_64_BIT: u32 = 1;
 pub const MEMP_NUM_TCP_PCB: u32 = 1024;
-pub const CHECKSUM_CHECK_IP: u32 = 0;
-pub const CHECKSUM_CHECK_UDP: u32 = 0;
-pub const CHECKSUM_CHECK_TCP: u32 = 0;
-pub const CHECKSUM_CHECK_ICMP: u32 = 0;
-pub const CHECKSUM_CHECK_ICMP6: u32 = 0;
+pub const LWIP_CHECKSUM_CTRL_PER_NETIF: u32 = 1;
+pub const CHECKSUM_CHECK_IP: u32 = 1;
+pub const CHECKSUM_CHECK_UDP: u32 = 1;
+pub const CHECKSUM_CHECK_TCP: u32 = 1;
+pub const CHECKSUM_CHECK_ICMP: u32 = 1;
+pub const CHECKSUM_CHECK_ICMP6: u32 = 1;
 pub const LWIP_CHECKSUM_ON_COPY: u32 = 1;
 pub const TCP_MSS: u32 = 1460;
-pub const TCP_WND: u32 = 46720;
-pub const TCP_SND_BUF: u32 = 11680;
+pub const TCP_WND: u32 = 262144;
+pub const TCP_SND_BUF: u32 = 23360;
 pub const MEM_SIZE: u32 = 2097152;
 pub const MEMP_NUM_TCP_SEG: u32 = 256;
 pub const PBUF_POOL_SIZE: u32 = 512;
 pub const SYS_LIGHTWEIGHT_PROT: u32 = 0;
 pub const IPV6_FRAG_COPYHEADER: u32 = 1;
 pub const LWIP_DEBUG: u32 = 0;
-pub const LWIP_STATS: u32 = 0;
+pub const LWIP_STATS: u32 = 1;
+pub const LWIP_STATS_LARGE: u32 = 1;
 pub const LWIP_STATS_DISPLAY: u32 = 0;
 pub const LWIP_PERF: u32 = 0;
 pub const LITTLE_ENDIAN: u32 = 1234;
@@ -1363,7 +1365,7 @@ pub const MEM_USE_POOLS_TRY_BIGGER_POOL: u32 = 0;
 pub const MEMP_USE_CUSTOM_POOLS: u32 = 0;
 pub const LWIP_ALLOW_MEM_FREE_FROM_OTHER_CONTEXT: u32 = 0;
 pub const MEMP_NUM_PBUF: u32 = 16;
-pub const MEMP_NUM_RAW_PCB: u32 = 4;
+pub const MEMP_NUM_RAW_PCB: u32 = 64;
 pub const MEMP_NUM_UDP_PCB: u32 = 4;
 pub const MEMP_NUM_TCP_PCB_LISTEN: u32 = 8;
 pub const MEMP_NUM_ALTCP_PCB: u32 = 1024;
@@ -1425,19 +1427,19 @@ pub const TCP_TTL: u32 = 64;
 pub const TCP_MAXRTX: u32 = 12;
 pub const TCP_SYNMAXRTX: u32 = 6;
 pub const TCP_QUEUE_OOSEQ: u32 = 1;
-pub const LWIP_TCP_SACK_OUT: u32 = 0;
+pub const LWIP_TCP_SACK_OUT: u32 = 1;
 pub const LWIP_TCP_MAX_SACK_NUM: u32 = 4;
 pub const TCP_CALCULATE_EFF_SEND_MSS: u32 = 1;
-pub const TCP_SND_QUEUELEN: u32 = 32;
+pub const TCP_SND_QUEUELEN: u32 = 1024;
 pub const TCP_OOSEQ_MAX_BYTES: u32 = 0;
 pub const TCP_OOSEQ_MAX_PBUFS: u32 = 0;
-pub const TCP_LISTEN_BACKLOG: u32 = 0;
+pub const TCP_LISTEN_BACKLOG: u32 = 1;
 pub const TCP_DEFAULT_LISTEN_BACKLOG: u32 = 255;
 pub const TCP_OVERSIZE: u32 = 1460;
 pub const LWIP_TCP_TIMESTAMPS: u32 = 0;
 pub const LWIP_EVENT_API: u32 = 0;
-pub const LWIP_WND_SCALE: u32 = 0;
-pub const TCP_RCV_SCALE: u32 = 0;
+pub const LWIP_WND_SCALE: u32 = 1;
+pub const TCP_RCV_SCALE: u32 = 3;
 pub const LWIP_TCP_PCB_NUM_EXT_ARGS: u32 = 0;
 pub const LWIP_ALTCP: u32 = 0;
 pub const LWIP_ALTCP_TLS: u32 = 0;
@@ -1474,7 +1476,7 @@ pub const LWIP_NETCONN_FULLDUPLEX: u32 = 0;
 pub const LWIP_COMPAT_SOCKETS: u32 = 1;
 pub const LWIP_POSIX_SOCKETS_IO_NAMES: u32 = 1;
 pub const LWIP_SOCKET_OFFSET: u32 = 0;
-pub const LWIP_TCP_KEEPALIVE: u32 = 0;
+pub const LWIP_TCP_KEEPALIVE: u32 = 1;
 pub const LWIP_SO_SNDTIMEO: u32 = 0;
 pub const LWIP_SO_RCVTIMEO: u32 = 0;
 pub const LWIP_SO_SNDRCVTIMEO_NONSTANDARD: u32 = 0;
@@ -1487,24 +1489,23 @@ pub const SO_REUSE_RXTOALL: u32 = 0;
 pub const LWIP_FIONREAD_LINUXMODE: u32 = 0;
 pub const LWIP_SOCKET_SELECT: u32 = 1;
 pub const LWIP_SOCKET_POLL: u32 = 1;
-pub const LINK_STATS: u32 = 0;
+pub const LINK_STATS: u32 = 1;
 pub const ETHARP_STATS: u32 = 0;
-pub const IP_STATS: u32 = 0;
-pub const IPFRAG_STATS: u32 = 0;
-pub const ICMP_STATS: u32 = 0;
+pub const IP_STATS: u32 = 1;
+pub const IPFRAG_STATS: u32 = 1;
+pub const ICMP_STATS: u32 = 1;
 pub const IGMP_STATS: u32 = 0;
-pub const UDP_STATS: u32 = 0;
-pub const TCP_STATS: u32 = 0;
-pub const MEM_STATS: u32 = 0;
-pub const MEMP_STATS: u32 = 0;
+pub const UDP_STATS: u32 = 1;
+pub const TCP_STATS: u32 = 1;
+pub const MEM_STATS: u32 = 1;
+pub const MEMP_STATS: u32 = 1;
 pub const SYS_STATS: u32 = 0;
-pub const IP6_STATS: u32 = 0;
-pub const ICMP6_STATS: u32 = 0;
-pub const IP6_FRAG_STATS: u32 = 0;
+pub const IP6_STATS: u32 = 1;
+pub const ICMP6_STATS: u32 = 1;
+pub const IP6_FRAG_STATS: u32 = 1;
 pub const MLD6_STATS: u32 = 0;
-pub const ND6_STATS: u32 = 0;
+pub const ND6_STATS: u32 = 1;
 pub const MIB2_STATS: u32 = 0;
-pub const LWIP_CHECKSUM_CTRL_PER_NETIF: u32 = 0;
 pub const CHECKSUM_GEN_IP: u32 = 1;
 pub const CHECKSUM_GEN_UDP: u32 = 1;
 pub const CHECKSUM_GEN_TCP: u32 = 1;
@@ -1651,6 +1652,18 @@ pub const NETIF_FLAG_ETHARP: u32 = 8;
 pub const NETIF_FLAG_ETHERNET: u32 = 16;
 pub const NETIF_FLAG_IGMP: u32 = 32;
 pub const NETIF_FLAG_MLD6: u32 = 64;
+pub const NETIF_CHECKSUM_GEN_IP: u32 = 1;
+pub const NETIF_CHECKSUM_GEN_UDP: u32 = 2;
+pub const NETIF_CHECKSUM_GEN_TCP: u32 = 4;
+pub const NETIF_CHECKSUM_GEN_ICMP: u32 = 8;
+pub const NETIF_CHECKSUM_GEN_ICMP6: u32 = 16;
+pub const NETIF_CHECKSUM_CHECK_IP: u32 = 256;
+pub const NETIF_CHECKSUM_CHECK_UDP: u32 = 512;
+pub const NETIF_CHECKSUM_CHECK_TCP: u32 = 1024;
+pub const NETIF_CHECKSUM_CHECK_ICMP: u32 = 2048;
+pub const NETIF_CHECKSUM_CHECK_ICMP6: u32 = 4096;
+pub const NETIF_CHECKSUM_ENABLE_ALL: u32 = 65535;
+pub const NETIF_CHECKSUM_DISABLE_ALL: u32 = 0;
 pub const NETIF_ADDR_IDX_MAX: u32 = 127;
 pub const LWIP_NETIF_USE_HINTS: u32 = 0;
 pub const NETIF_NO_INDEX: u32 = 0;
@@ -1735,7 +1748,10 @@ pub const TF_RXCLOSED: u32 = 16;
 pub const TF_FIN: u32 = 32;
 pub const TF_NODELAY: u32 = 64;
 pub const TF_NAGLEMEMERR: u32 = 128;
+pub const TF_WND_SCALE: u32 = 256;
+pub const TF_BACKLOGPEND: u32 = 512;
 pub const TF_RTO: u32 = 2048;
+pub const TF_SACK: u32 = 4096;
 pub const TCP_SNDQUEUELEN_OVERFLOW: u32 = 65532;
 pub const UDP_HLEN: u32 = 8;
 pub const UDP_FLAGS_NOCHKSUM: u32 = 1;
@@ -5666,6 +5682,7 @@ pub struct netif {
     #[doc = " This field can be set by the device driver and could point"]
     #[doc = "  to state information for the device."]
     pub state: *mut ::std::os::raw::c_void,
+    pub chksum_flags: u16_t,
     #[doc = " maximum transfer unit (in bytes)"]
     pub mtu: u16_t,
     #[doc = " maximum transfer unit (in bytes), updated by RA"]
@@ -5880,7 +5897,7 @@ pub type netif_ext_callback_fn = ::std::option::Option<
         args: *const netif_ext_callback_args_t,
     ),
 >;
-pub type tcpwnd_size_t = u16_t;
+pub type tcpwnd_size_t = u32_t;
 pub const tcp_state_CLOSED: tcp_state = 0;
 pub const tcp_state_LISTEN: tcp_state = 1;
 pub const tcp_state_SYN_SENT: tcp_state = 2;
@@ -6359,6 +6376,16 @@ pub struct tcp_ext_arg_callbacks {
     #[doc = " @ref tcp_extarg_callback_passive_open_fn"]
     pub passive_open: tcp_extarg_callback_passive_open_fn,
 }
+#[doc = " SACK ranges to include in ACK packets."]
+#[doc = " SACK entry is invalid if left==right."]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct tcp_sack_range {
+    #[doc = " Left edge of the SACK: the first acknowledged sequence number."]
+    pub left: u32_t,
+    #[doc = " Right edge of the SACK: the last acknowledged sequence number +1 (so first NOT acknowledged)."]
+    pub right: u32_t,
+}
 pub type tcpflags_t = u16_t;
 #[doc = " the TCP protocol control block for listening pcbs"]
 #[repr(C)]
@@ -6376,6 +6403,8 @@ pub struct tcp_pcb_listen {
     pub prio: u8_t,
     pub local_port: u16_t,
     pub accept: tcp_accept_fn,
+    pub backlog: u8_t,
+    pub accepts_pending: u8_t,
 }
 #[doc = " the TCP protocol control block"]
 #[repr(C)]
@@ -6402,6 +6431,7 @@ pub struct tcp_pcb {
     pub rcv_wnd: tcpwnd_size_t,
     pub rcv_ann_wnd: tcpwnd_size_t,
     pub rcv_ann_right_edge: u32_t,
+    pub rcv_sacks: [tcp_sack_range; 4usize],
     pub rtime: s16_t,
     pub mss: u16_t,
     pub rttest: u32_t,
@@ -6436,10 +6466,14 @@ pub struct tcp_pcb {
     pub poll: tcp_poll_fn,
     pub errf: tcp_err_fn,
     pub keep_idle: u32_t,
+    pub keep_intvl: u32_t,
+    pub keep_cnt: u32_t,
     pub persist_cnt: u8_t,
     pub persist_backoff: u8_t,
     pub persist_probe: u8_t,
     pub keep_cnt_sent: u8_t,
+    pub snd_scale: u8_t,
+    pub rcv_scale: u8_t,
 }
 extern "C" {
     pub fn tcp_new() -> *mut tcp_pcb;
@@ -6690,10 +6724,191 @@ extern "C" {
 extern "C" {
     pub fn udp_netif_ip_addr_changed(old_addr: *const ip_addr_t, new_addr: *const ip_addr_t);
 }
-pub type __builtin_va_list = *mut ::std::os::raw::c_char;
-pub type __uint128_t = u128;
+pub type raw_recv_fn = ::std::option::Option<
+    unsafe extern "C" fn(
+        arg: *mut ::std::os::raw::c_void,
+        pcb: *mut raw_pcb,
+        p: *mut pbuf,
+        addr: *const ip_addr_t,
+    ) -> u8_t,
+>;
+#[doc = " the RAW protocol control block"]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct raw_pcb {
+    pub local_ip: ip_addr_t,
+    pub remote_ip: ip_addr_t,
+    pub netif_idx: u8_t,
+    pub so_options: u8_t,
+    pub tos: u8_t,
+    pub ttl: u8_t,
+    pub next: *mut raw_pcb,
+    pub protocol: u8_t,
+    pub flags: u8_t,
+    #[doc = " receive callback function"]
+    pub recv: raw_recv_fn,
+    pub recv_arg: *mut ::std::os::raw::c_void,
+    pub chksum_offset: u16_t,
+    pub chksum_reqd: u8_t,
+}
+extern "C" {
+    pub fn raw_new(proto: u8_t) -> *mut raw_pcb;
+}
+extern "C" {
+    pub fn raw_new_ip_type(type_: u8_t, proto: u8_t) -> *mut raw_pcb;
+}
+extern "C" {
+    pub fn raw_remove(pcb: *mut raw_pcb);
+}
+extern "C" {
+    pub fn raw_bind(pcb: *mut raw_pcb, ipaddr: *const ip_addr_t) -> err_t;
+}
+extern "C" {
+    pub fn raw_bind_netif(pcb: *mut raw_pcb, netif: *const netif);
+}
+extern "C" {
+    pub fn raw_connect(pcb: *mut raw_pcb, ipaddr: *const ip_addr_t) -> err_t;
+}
+extern "C" {
+    pub fn raw_disconnect(pcb: *mut raw_pcb);
+}
+extern "C" {
+    pub fn raw_sendto(pcb: *mut raw_pcb, p: *mut pbuf, ipaddr: *const ip_addr_t) -> err_t;
+}
+extern "C" {
+    pub fn raw_sendto_if_src(
+        pcb: *mut raw_pcb,
+        p: *mut pbuf,
+        dst_ip: *const ip_addr_t,
+        netif: *mut netif,
+        src_ip: *const ip_addr_t,
+    ) -> err_t;
+}
+extern "C" {
+    pub fn raw_send(pcb: *mut raw_pcb, p: *mut pbuf) -> err_t;
+}
+extern "C" {
+    pub fn raw_recv(pcb: *mut raw_pcb, recv: raw_recv_fn, recv_arg: *mut ::std::os::raw::c_void);
+}
+pub const TCP_FIN: u32 = 1;
+pub const TCP_SYN: u32 = 2;
+pub const TCP_RST: u32 = 4;
+pub const TCP_PSH: u32 = 8;
+pub const TCP_ACK: u32 = 16;
+pub const TCP_URG: u32 = 32;
+pub const TCP_HLEN: u32 = 20;
+pub const TCP_TMR_INTERVAL: u32 = 250;
+pub const TCP_FAST_INTERVAL: u32 = 250;
+pub const TCP_SLOW_INTERVAL: u32 = 500;
+pub const LWIP_TCP_OPT_EOL: u32 = 0;
+pub const LWIP_TCP_OPT_NOP: u32 = 1;
+pub const LWIP_TCP_OPT_MSS: u32 = 2;
+pub const LWIP_TCP_OPT_WS: u32 = 3;
+pub const LWIP_TCP_OPT_SACK_PERM: u32 = 4;
+pub const LWIP_TCP_OPT_TS: u32 = 8;
+#[doc = " Fields are (of course) in network byte order."]
+#[doc = " Some fields are converted to host byte order in tcp_input()."]
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct tcp_hdr {
+    pub src: u16_t,
+    pub dest: u16_t,
+    pub seqno: u32_t,
+    pub ackno: u32_t,
+    pub _hdrlen_rsvd_flags: u16_t,
+    pub wnd: u16_t,
+    pub chksum: u16_t,
+    pub urgp: u16_t,
+}
+extern "C" {
+    pub fn tcp_rst(
+        pcb: *const tcp_pcb,
+        seqno: u32_t,
+        ackno: u32_t,
+        local_ip: *const ip_addr_t,
+        remote_ip: *const ip_addr_t,
+        local_port: u16_t,
+        remote_port: u16_t,
+    );
+}
+extern "C" {
+    pub fn tcp_txnow();
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union tcp_listen_pcbs_t {
+    pub listen_pcbs: *mut tcp_pcb_listen,
+    pub pcbs: *mut tcp_pcb,
+}
+extern "C" {
+    pub static mut tcp_bound_pcbs: *mut tcp_pcb;
+}
+extern "C" {
+    pub static mut tcp_listen_pcbs: tcp_listen_pcbs_t;
+}
+extern "C" {
+    pub static mut tcp_active_pcbs: *mut tcp_pcb;
+}
+extern "C" {
+    pub static mut tcp_tw_pcbs: *mut tcp_pcb;
+}
+extern "C" {
+    pub static mut tcp_ticks: u32_t;
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct netstack_proto_stats {
+    pub xmit: u32_t,
+    pub recv: u32_t,
+    pub drop: u32_t,
+    pub err: u32_t,
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct netstack_mem_stats {
+    pub avail: u32_t,
+    pub used: u32_t,
+    pub max: u32_t,
+    pub err: u32_t,
+}
+#[doc = " Flat copy of lwip_stats, so that the Rust side does not depend on its layout."]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct netstack_lwip_stats {
+    pub ip4: netstack_proto_stats,
+    pub ip6: netstack_proto_stats,
+    pub icmp: netstack_proto_stats,
+    pub icmp6: netstack_proto_stats,
+    pub tcp: netstack_proto_stats,
+    pub udp: netstack_proto_stats,
+    pub heap: netstack_mem_stats,
+    pub pbuf_pool: netstack_mem_stats,
+    pub pbuf: netstack_mem_stats,
+    pub raw_pcb: netstack_mem_stats,
+    pub udp_pcb: netstack_mem_stats,
+    pub tcp_pcb: netstack_mem_stats,
+    pub tcp_pcb_listen: netstack_mem_stats,
+    pub tcp_seg: netstack_mem_stats,
+}
+extern "C" {
+    pub fn netstack_get_lwip_stats(out: *mut netstack_lwip_stats);
+}
+extern "C" {
+    pub fn netstack_chkerr() -> u32_t;
+}
+extern "C" {
+    pub fn netstack_tcp_pcb_pool_full() -> u8_t;
+}
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct tcp_seg {
-    pub _address: u8,
+    pub next: *mut tcp_seg,
+    pub p: *mut pbuf,
+    pub len: u16_t,
+    pub chksum: u16_t,
+    pub chksum_swapped: u8_t,
+    pub flags: u8_t,
+    pub tcphdr: *mut tcp_hdr,
 }
+pub type __builtin_va_list = *mut ::std::os::raw::c_char;
+pub type __uint128_t = u128;
@@ -0,0 +1,233 @@
+use std::pin::Pin;
+use std::time::Duration;
+
+use super::sans_io::SyncNetStack;
+use super::stack::NetStack;
+use super::tcp_listener::TcpListener;
+use super::udp::UdpSocket;
+use crate::Error;
+
+/// Options applied to every TCP connection of a stack.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TcpConfig {
+    pub send_buffer: Option<usize>,
+    pub window_scaling: bool,
+    pub sack: bool,
+    pub keepalive: Option<Keepalive>,
+    pub pcb_eviction: bool,
+}
+
+/// Keepalive parameters, see `NetStackBuilder::keepalive`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            send_buffer: None,
+            window_scaling: true,
+            sack: true,
+            keepalive: None,
+            pcb_eviction: false,
+        }
+    }
+}
+
+/// Builds a `NetStack` along with its TCP listener and UDP socket.
+///
+/// lwIP's memory pools, `TCP_MSS` and `TCP_WND` are fixed at compile time,
+/// see `lwipopts.h`. This covers what can be set per stack.
+#[derive(Debug, Clone)]
+pub struct NetStackBuilder {
+    pub(crate) mtu: u16,
+    pub(crate) tcp: TcpConfig,
+    pub(crate) backlog: u8,
+    pub(crate) timer_period: Duration,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) buffer_size: usize,
+    pub(crate) udp_buffer_size: usize,
+    pub(crate) checksum_validation: bool,
+    pub(crate) virtio_net_hdr: bool,
+}
+
+impl Default for NetStackBuilder {
+    fn default() -> Self {
+        NetStackBuilder {
+            mtu: 1500,
+            tcp: TcpConfig::default(),
+            backlog: 0,
+            timer_period: Duration::from_millis(250),
+            idle_timeout: None,
+            buffer_size: 512,
+            udp_buffer_size: 64,
+            checksum_validation: false,
+            virtio_net_hdr: false,
+        }
+    }
+}
+
+impl NetStackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// MTU of the netif, which also bounds the MSS of TCP connections.
+    /// Defaults to 1500.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Bytes each TCP connection may have queued for sending. Defaults to
+    /// `TCP_SND_BUF`. Larger values are still bounded by the number of
+    /// segments lwIP queues per connection, `TCP_SND_QUEUELEN`.
+    pub fn send_buffer(mut self, size: usize) -> Self {
+        self.tcp.send_buffer = Some(size);
+        self
+    }
+
+    /// Whether to accept the window scale option (RFC 7323) of incoming
+    /// connections. Scaling lets a connection have up to `TCP_WND` (256KB)
+    /// in flight toward the stack instead of 64KB, which is what limits
+    /// throughput on links with a high bandwidth-delay product. Defaults
+    /// to true.
+    ///
+    /// Only applies to accepted connections, lwIP always offers scaling on
+    /// the ones it opens.
+    pub fn window_scaling(mut self, enabled: bool) -> Self {
+        self.tcp.window_scaling = enabled;
+        self
+    }
+
+    /// Whether to accept selective acknowledgements (RFC 2018) on incoming
+    /// connections. lwIP then reports out-of-order data it has received, so
+    /// a peer sending to the stack only retransmits what was lost. lwIP does
+    /// not act on the SACK blocks it receives. Defaults to true.
+    ///
+    /// Only applies to accepted connections, like `window_scaling`.
+    pub fn sack(mut self, enabled: bool) -> Self {
+        self.tcp.sack = enabled;
+        self
+    }
+
+    /// Sends keepalive probes on every TCP connection after `idle` without
+    /// traffic from the peer, every `interval`, and aborts the connection
+    /// once `count` probes went unanswered. Disabled by default, except on
+    /// iOS where lwIP's defaults (2 hours, 75 seconds, 9 probes) apply.
+    ///
+    /// Can be changed per connection with `TcpStream::set_keepalive`.
+    pub fn keepalive(mut self, idle: Duration, interval: Duration, count: u32) -> Self {
+        self.tcp.keepalive = Some(Keepalive {
+            idle,
+            interval,
+            count,
+        });
+        self
+    }
+
+    /// Aborts TCP connections that received nothing from the peer for
+    /// `timeout`, including those whose stream has been dropped or not
+    /// accepted yet. The streams of aborted connections then fail with
+    /// `Error::IdleTimeout`, of kind `TimedOut`. Answered keepalive probes
+    /// count as activity. Disabled by default.
+    ///
+    /// lwIP tracks activity in ticks of 500ms, so is the timeout.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Whether a new connection may evict an existing one of the stack when
+    /// lwIP has no pcb left (`MEMP_NUM_TCP_PCB`). The oldest connection in
+    /// TIME_WAIT is evicted first, then the established connection that has
+    /// been idle the longest, which is reset. Without eviction, lwIP only
+    /// reuses TIME_WAIT pcbs and drops the SYN otherwise. Defaults to
+    /// false.
+    ///
+    /// See `TcpListener::set_eviction_callback` to be told about evictions.
+    pub fn pcb_eviction(mut self, enabled: bool) -> Self {
+        self.tcp.pcb_eviction = enabled;
+        self
+    }
+
+    /// Connections in the middle of their handshake that the listener
+    /// tolerates, further SYNs are dropped. Defaults to 0, which sets no
+    /// limit.
+    pub fn backlog(mut self, backlog: u8) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Interval at which connections are checked against `idle_timeout`.
+    /// lwIP's own timers run on the driver thread whenever they are due, or
+    /// from `NetStack::poll_timers` without the `tokio` feature. Defaults to
+    /// 250ms.
+    pub fn timer_period(mut self, period: Duration) -> Self {
+        self.timer_period = period.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Capacity of the queue of packets emitted by the stack. Defaults to
+    /// 512.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Capacity of the queue of datagrams received by the UDP socket.
+    /// Defaults to 64.
+    pub fn udp_buffer_size(mut self, size: usize) -> Self {
+        self.udp_buffer_size = size;
+        self
+    }
+
+    /// Whether to check the IP, TCP, UDP and ICMP checksums of the packets
+    /// written into the stack, dropping those that do not match. Worth it
+    /// when the TUN side may corrupt packets, e.g. when tunneled over a
+    /// serial link. Dropped packets are counted in
+    /// `Stats::checksum_errors`. Defaults to false, the TUN device being
+    /// trusted.
+    pub fn checksum_validation(mut self, enabled: bool) -> Self {
+        self.checksum_validation = enabled;
+        self
+    }
+
+    /// Whether the packets written into the sink and read from the stream
+    /// start with a `virtio_net_hdr`, as those of a Linux TUN device opened
+    /// with `IFF_VNET_HDR` and the default header size of 10 bytes.
+    /// Defaults to false.
+    ///
+    /// The TCP checksum of emitted packets is then left to the kernel, which
+    /// requires `TUN_F_CSUM` to be set with `TUNSETOFFLOAD`. Packets whose
+    /// checksum the kernel left partial or already validated are accepted
+    /// as they are, as are TCP segments coalesced with `TUN_F_TSO4` and
+    /// `TUN_F_TSO6`. UDP segmentation offload is not supported, such
+    /// packets are counted in `Stats::input_errors`.
+    pub fn virtio_net_hdr(mut self, enabled: bool) -> Self {
+        self.virtio_net_hdr = enabled;
+        self
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn build(
+        self,
+    ) -> Result<
+        (
+            Pin<Box<NetStack>>,
+            Pin<Box<TcpListener>>,
+            Pin<Box<UdpSocket>>,
+        ),
+        Error,
+    > {
+        NetStack::from_builder(self)
+    }
+
+    /// Builds a stack driven synchronously, see `SyncNetStack`.
+    pub fn build_sync(self) -> Result<SyncNetStack, Error> {
+        SyncNetStack::new(self)
+    }
+}
@@ -0,0 +1,252 @@
+//! Captures of the packets crossing the boundary of a `NetStack`.
+//!
+//! Packets are raw IPv4/IPv6, written with the `LINKTYPE_RAW` link type and
+//! microsecond timestamps from the system clock.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use log::warn;
+
+const LINKTYPE_RAW: u16 = 101;
+const SNAPLEN: u32 = 65535;
+
+/// Which way a packet crossed the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Written into the sink, from the TUN side.
+    Inbound,
+    /// Emitted toward the TUN side.
+    Outbound,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Pcap,
+    Pcapng,
+}
+
+struct Record {
+    time: SystemTime,
+    direction: Direction,
+    pkt: Bytes,
+}
+
+enum Target {
+    Writer {
+        writer: Box<dyn Write + Send>,
+        format: Format,
+    },
+    Ring {
+        records: VecDeque<Record>,
+        limit: usize,
+    },
+    /// The writer failed, nothing is captured anymore.
+    Closed,
+}
+
+/// Where the packets of a stack are captured, see `NetStack::set_capture`.
+///
+/// Cheap to clone, all clones share the same capture.
+#[derive(Clone)]
+pub struct Capture {
+    target: Arc<Mutex<Target>>,
+}
+
+impl Capture {
+    /// Writes the packets to `writer` in the pcap format, which has no room
+    /// for their direction. The file header is written right away.
+    pub fn pcap(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        Self::writer(Box::new(writer), Format::Pcap)
+    }
+
+    /// Writes the packets to `writer` in the pcapng format, along with their
+    /// direction. The section and interface headers are written right away.
+    pub fn pcapng(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        Self::writer(Box::new(writer), Format::Pcapng)
+    }
+
+    fn writer(mut writer: Box<dyn Write + Send>, format: Format) -> io::Result<Self> {
+        writer.write_all(&header(format))?;
+        Ok(Capture {
+            target: Arc::new(Mutex::new(Target::Writer { writer, format })),
+        })
+    }
+
+    /// Keeps the last `limit` packets in memory, for `dump` to write them
+    /// out when needed, e.g. from a panic hook.
+    pub fn ring(limit: usize) -> Self {
+        Capture {
+            target: Arc::new(Mutex::new(Target::Ring {
+                records: VecDeque::with_capacity(limit.min(1024)),
+                limit: limit.max(1),
+            })),
+        }
+    }
+
+    /// Writes the packets kept by a ring capture to `writer` as pcapng,
+    /// oldest first. Does nothing for other captures.
+    pub fn dump(&self, mut writer: impl Write) -> io::Result<()> {
+        let target = self.target.lock().unwrap();
+        if let Target::Ring { records, .. } = &*target {
+            let mut buf = header(Format::Pcapng);
+            for record in records {
+                encode(Format::Pcapng, record, &mut buf);
+            }
+            writer.write_all(&buf)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the writer of the capture.
+    pub fn flush(&self) -> io::Result<()> {
+        match &mut *self.target.lock().unwrap() {
+            Target::Writer { writer, .. } => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, pkt: &Bytes) {
+        let record = Record {
+            time: SystemTime::now(),
+            direction,
+            pkt: pkt.clone(),
+        };
+        let target = &mut *self.target.lock().unwrap();
+        match target {
+            Target::Writer { writer, format } => {
+                let mut buf = Vec::with_capacity(pkt.len() + 48);
+                encode(*format, &record, &mut buf);
+                if let Err(e) = writer.write_all(&buf) {
+                    warn!("netstack capture stopped: {}", e);
+                    *target = Target::Closed;
+                }
+            }
+            Target::Ring { records, limit } => {
+                if records.len() >= *limit {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            }
+            Target::Closed => {}
+        }
+    }
+}
+
+fn header(format: Format) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(48);
+    match format {
+        Format::Pcap => {
+            buf.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+            buf.extend_from_slice(&2u16.to_le_bytes());
+            buf.extend_from_slice(&4u16.to_le_bytes());
+            // Time zone offset and timestamp accuracy.
+            buf.extend_from_slice(&[0; 8]);
+            buf.extend_from_slice(&SNAPLEN.to_le_bytes());
+            buf.extend_from_slice(&(LINKTYPE_RAW as u32).to_le_bytes());
+        }
+        Format::Pcapng => {
+            // Section header block, of unspecified length.
+            block(&mut buf, 0x0a0d0d0a, |b| {
+                b.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+                b.extend_from_slice(&1u16.to_le_bytes());
+                b.extend_from_slice(&0u16.to_le_bytes());
+                b.extend_from_slice(&u64::MAX.to_le_bytes());
+            });
+            // Interface description block, with the default microsecond
+            // resolution.
+            block(&mut buf, 1, |b| {
+                b.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+                b.extend_from_slice(&0u16.to_le_bytes());
+                b.extend_from_slice(&SNAPLEN.to_le_bytes());
+            });
+        }
+    }
+    buf
+}
+
+fn encode(format: Format, record: &Record, buf: &mut Vec<u8>) {
+    let micros = record
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let len = record.pkt.len() as u32;
+    let caplen = len.min(SNAPLEN);
+    let data = &record.pkt[..caplen as usize];
+    match format {
+        Format::Pcap => {
+            buf.extend_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+            buf.extend_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+            buf.extend_from_slice(&caplen.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(data);
+        }
+        Format::Pcapng => {
+            // Enhanced packet block on interface 0, with an epb_flags option
+            // telling the direction.
+            block(buf, 6, |b| {
+                b.extend_from_slice(&0u32.to_le_bytes());
+                b.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+                b.extend_from_slice(&(micros as u32).to_le_bytes());
+                b.extend_from_slice(&caplen.to_le_bytes());
+                b.extend_from_slice(&len.to_le_bytes());
+                b.extend_from_slice(data);
+                b.resize(b.len().next_multiple_of(4), 0);
+                let flags: u32 = match record.direction {
+                    Direction::Inbound => 1,
+                    Direction::Outbound => 2,
+                };
+                b.extend_from_slice(&2u16.to_le_bytes());
+                b.extend_from_slice(&4u16.to_le_bytes());
+                b.extend_from_slice(&flags.to_le_bytes());
+                b.extend_from_slice(&[0; 4]);
+            });
+        }
+    }
+}
+
+/// Appends a pcapng block of `kind`, its body written by `body`.
+fn block(buf: &mut Vec<u8>, kind: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(&[0; 4]);
+    body(buf);
+    let len = (buf.len() - start + 4) as u32;
+    buf[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_dump() {
+        let capture = Capture::ring(2);
+        for (i, direction) in [Direction::Inbound, Direction::Outbound, Direction::Inbound]
+            .into_iter()
+            .enumerate()
+        {
+            capture.record(direction, &Bytes::from(vec![0x45; i + 1]));
+        }
+        let mut out = Vec::new();
+        capture.dump(&mut out).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes(out[i..i + 4].try_into().unwrap());
+        // Section header, interface description, then the last two packets.
+        assert_eq!((u32_at(0), u32_at(4)), (0x0a0d0d0a, 28));
+        assert_eq!((u32_at(28), u32_at(32)), (1, 20));
+        let mut at = 48;
+        for (len, flags) in [(2, 2), (3, 1)] {
+            assert_eq!((u32_at(at), u32_at(at + 4)), (6, 48));
+            assert_eq!(u32_at(at + 20), len);
+            assert_eq!(u32_at(at + 36), flags);
+            at += 48;
+        }
+        assert_eq!(out.len(), at);
+    }
+}
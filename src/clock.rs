@@ -0,0 +1,114 @@
+//! The time read by lwIP's timers and the periodic work of the stacks.
+//!
+//! lwIP's `sys_now` is implemented here. It reads the system's monotonic
+//! clock unless another one was set with `NetStack::set_clock`.
+
+use std::any::Any;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use super::driver;
+use super::lwip::u32_t;
+
+/// A source of time for the stacks.
+pub trait Clock: Send + Sync {
+    /// Time elapsed since an arbitrary origin. Must never go backwards.
+    fn now(&self) -> Duration;
+}
+
+/// A clock that only moves when told to, so that tests can fast-forward
+/// retransmission timeouts, TIME_WAIT or keepalives.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    /// Creates a clock starting at the time of the current one, so that it
+    /// can replace it without making the pending timeouts jump.
+    pub fn new() -> Self {
+        VirtualClock {
+            nanos: Arc::new(AtomicU64::new(now().as_nanos() as u64)),
+        }
+    }
+
+    /// Moves the clock forward by `elapsed`, stopping at every timer due in
+    /// between to run it, as if the time had passed for real.
+    ///
+    /// Only the clock set with `NetStack::set_clock` drives the timers.
+    pub fn advance(&self, elapsed: Duration) {
+        self.step_to(Clock::now(self) + elapsed);
+    }
+
+    /// Returns how long until the next timer is due.
+    fn step_to(&self, target: Duration) -> Duration {
+        driver::call(|| loop {
+            let wait = driver::run_timers();
+            let now = Clock::now(self);
+            if now >= target {
+                return wait;
+            }
+            let next = target.min(now + wait.max(Duration::from_millis(1)));
+            self.nanos.store(next.as_nanos() as u64, Ordering::Release);
+        })
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Acquire))
+    }
+}
+
+enum Source {
+    System,
+    Virtual(VirtualClock),
+    Custom(Box<dyn Clock>),
+}
+
+static SOURCE: RwLock<Source> = RwLock::new(Source::System);
+static START: OnceLock<Instant> = OnceLock::new();
+
+pub(crate) fn now() -> Duration {
+    match &*SOURCE.read().unwrap() {
+        Source::System => START.get_or_init(Instant::now).elapsed(),
+        Source::Virtual(clock) => clock.now(),
+        Source::Custom(clock) => clock.now(),
+    }
+}
+
+pub(crate) fn set(clock: impl Clock + 'static) {
+    let source = match (&clock as &dyn Any).downcast_ref::<VirtualClock>() {
+        Some(clock) => Source::Virtual(clock.clone()),
+        None => Source::Custom(Box::new(clock)),
+    };
+    *SOURCE.write().unwrap() = source;
+}
+
+/// Lets `elapsed` pass on a `VirtualClock`, or only runs the timers due
+/// with any other clock. Returns how long until the next timer is due.
+pub(crate) fn advance(elapsed: Duration) -> Duration {
+    // The timers read the clock too, the lock is released before they run.
+    let source = SOURCE.read().unwrap();
+    let clock = match &*source {
+        Source::Virtual(clock) => Some(clock.clone()),
+        _ => None,
+    };
+    drop(source);
+    match clock {
+        Some(clock) => clock.step_to(clock.now() + elapsed),
+        None => driver::call(driver::run_timers),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_now() -> u32_t {
+    now().as_millis() as u32_t
+}
@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::lwip::*;
+use super::util::to_socket_addr;
+
+/// State of a TCP connection, as tracked by lwIP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynRcvd,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl From<tcp_state> for TcpState {
+    #[allow(non_upper_case_globals)]
+    fn from(state: tcp_state) -> Self {
+        match state {
+            tcp_state_LISTEN => TcpState::Listen,
+            tcp_state_SYN_SENT => TcpState::SynSent,
+            tcp_state_SYN_RCVD => TcpState::SynRcvd,
+            tcp_state_ESTABLISHED => TcpState::Established,
+            tcp_state_FIN_WAIT_1 => TcpState::FinWait1,
+            tcp_state_FIN_WAIT_2 => TcpState::FinWait2,
+            tcp_state_CLOSE_WAIT => TcpState::CloseWait,
+            tcp_state_CLOSING => TcpState::Closing,
+            tcp_state_LAST_ACK => TcpState::LastAck,
+            tcp_state_TIME_WAIT => TcpState::TimeWait,
+            _ => TcpState::Closed,
+        }
+    }
+}
+
+/// Snapshot of one TCP connection, see `NetStack::connections`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Same as `TcpStream::local_addr`, the source of the flow.
+    pub local_addr: SocketAddr,
+    /// Same as `TcpStream::remote_addr`, the destination of the flow.
+    pub remote_addr: SocketAddr,
+    pub state: TcpState,
+    /// Bytes that can still be written before the send buffer is full.
+    pub snd_buf: usize,
+    /// Window advertised by the peer.
+    pub snd_wnd: usize,
+    /// Pbufs queued for sending, as counted against `TCP_SND_QUEUELEN`.
+    pub snd_queuelen: usize,
+    /// Segments not yet sent.
+    pub unsent_segs: usize,
+    /// Segments sent but not yet acknowledged.
+    pub unacked_segs: usize,
+    /// Smoothed round-trip time, with lwIP's coarse timer granularity
+    /// (`TCP_SLOW_INTERVAL`, 500ms).
+    pub rtt: Duration,
+    /// Current retransmission timeout.
+    pub rto: Duration,
+    pub cwnd: usize,
+    pub ssthresh: usize,
+    /// Consecutive retransmissions of the oldest unacknowledged segment,
+    /// reset once it is acknowledged.
+    pub retransmits: u8,
+}
+
+/// Lists the active and TIME_WAIT pcbs bound to the netif, on the driver
+/// thread.
+pub(crate) unsafe fn tcp_connections(netif_idx: u8) -> Vec<ConnectionInfo> {
+    let mut conns = Vec::new();
+    for list in [tcp_active_pcbs, tcp_tw_pcbs] {
+        let mut pcb = list;
+        while !pcb.is_null() {
+            let p = std::ptr::read_unaligned(pcb);
+            if p.netif_idx == netif_idx {
+                conns.push(ConnectionInfo {
+                    // Swapped, as in `TcpStream::new`.
+                    local_addr: to_socket_addr(&p.remote_ip, p.remote_port),
+                    remote_addr: to_socket_addr(&p.local_ip, p.local_port),
+                    state: p.state.into(),
+                    snd_buf: p.snd_buf as usize,
+                    snd_wnd: p.snd_wnd as usize,
+                    snd_queuelen: p.snd_queuelen as usize,
+                    unsent_segs: count_segs(p.unsent),
+                    unacked_segs: count_segs(p.unacked),
+                    rtt: ticks((p.sa >> 3) as i64),
+                    rto: ticks(p.rto as i64),
+                    cwnd: p.cwnd as usize,
+                    ssthresh: p.ssthresh as usize,
+                    retransmits: p.nrtx,
+                });
+            }
+            pcb = p.next;
+        }
+    }
+    conns
+}
+
+unsafe fn count_segs(mut seg: *mut tcp_seg) -> usize {
+    let mut n = 0;
+    while !seg.is_null() {
+        n += 1;
+        seg = std::ptr::read_unaligned(seg).next;
+    }
+    n
+}
+
+fn ticks(n: i64) -> Duration {
+    Duration::from_millis(n.max(0) as u64 * TCP_SLOW_INTERVAL as u64)
+}
@@ -0,0 +1,199 @@
+//! The thread running the lwIP core.
+//!
+//! lwIP is not thread safe, and its core (pcb lists, memory pools, timers)
+//! is shared by every `NetStack` of the process. A single thread owns it: the
+//! rest of the crate sends it commands over a channel, and it runs them along
+//! with lwIP's timers. lwIP calls therefore never contend, and lwIP callbacks
+//! always run on that thread.
+//!
+//! Without the `tokio` feature, the timers still run on this thread, but
+//! only when the caller asks for it, see `NetStack::poll_timers`.
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use super::clock;
+use super::lwip::*;
+
+type Command = Box<dyn FnOnce() + Send>;
+
+/// Whether the driver runs the timers itself.
+const DRIVES_TIMERS: bool = cfg!(feature = "tokio");
+
+/// Longest wait for lwIP's timeouts, which its cyclic timers keep short.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+static DRIVER: OnceLock<Sender<Command>> = OnceLock::new();
+
+thread_local! {
+    static ON_DRIVER: Cell<bool> = const { Cell::new(false) };
+    // Periodic work of the stacks, only ever touched by the driver.
+    static TICKERS: RefCell<Vec<Ticker>> = const { RefCell::new(Vec::new()) };
+    static NEXT_TICKER_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+struct Ticker {
+    id: u64,
+    period: Duration,
+    /// Due time, on the clock of the stacks.
+    next: Duration,
+    tick: Box<dyn FnMut()>,
+}
+
+fn sender() -> &'static Sender<Command> {
+    DRIVER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("netstack-lwip".into())
+            .spawn(move || run(rx))
+            .expect("failed to spawn the netstack driver thread");
+        tx
+    })
+}
+
+fn on_driver() -> bool {
+    ON_DRIVER.with(|d| d.get())
+}
+
+fn run(rx: Receiver<Command>) {
+    ON_DRIVER.with(|d| d.set(true));
+    unsafe { lwip_init() };
+    let mut next_timer = Instant::now();
+    loop {
+        let sleep = if DRIVES_TIMERS {
+            next_timer.saturating_duration_since(Instant::now())
+        } else {
+            Duration::MAX
+        };
+        match rx.recv_timeout(sleep) {
+            Ok(cmd) => {
+                if panic::catch_unwind(AssertUnwindSafe(cmd)).is_err() {
+                    error!("netstack driver command panicked");
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if DRIVES_TIMERS {
+            next_timer = Instant::now() + run_timers();
+        }
+    }
+}
+
+/// Runs lwIP's timeouts and the tickers that are due, returns how long until
+/// the next one is. Must be called on the driver thread.
+pub(crate) fn run_timers() -> Duration {
+    debug_assert!(on_driver());
+    let millis = unsafe {
+        sys_check_timeouts();
+        sys_timeouts_sleeptime()
+    };
+    let mut wait = MAX_SLEEP.min(Duration::from_millis(millis as u64));
+    let now = clock::now();
+    TICKERS.with(|tickers| {
+        for ticker in tickers.borrow_mut().iter_mut() {
+            if ticker.next <= now {
+                (ticker.tick)();
+                ticker.next = now + ticker.period;
+            }
+            wait = wait.min(ticker.next - now);
+        }
+    });
+    wait
+}
+
+/// Runs `f` on the driver thread without waiting for it. Commands run in
+/// the order they were submitted, those submitted from the driver thread
+/// itself run once the current one returns.
+pub(crate) fn submit(f: impl FnOnce() + Send + 'static) {
+    submit_boxed(Box::new(f));
+}
+
+/// Runs `f` on the driver thread and waits for its result, or runs it right
+/// away when already on the driver thread.
+pub(crate) fn call<'a, R: Send + 'a>(f: impl FnOnce() -> R + Send + 'a) -> R {
+    if on_driver() {
+        return f();
+    }
+    let (tx, rx) = mpsc::sync_channel(1);
+    let cmd: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+        let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    });
+    // SAFETY: the command may borrow from the caller, which waits below until
+    // the command has run, or has been dropped without running.
+    let cmd: Command = unsafe { std::mem::transmute(cmd) };
+    submit_boxed(cmd);
+    match rx.recv() {
+        Ok(Ok(r)) => r,
+        Ok(Err(payload)) => panic::resume_unwind(payload),
+        Err(_) => panic!("netstack driver thread is gone"),
+    }
+}
+
+fn submit_boxed(cmd: Command) {
+    sender().send(cmd).expect("netstack driver thread is gone");
+}
+
+/// Runs `tick` every `period` on the driver thread, until `remove_ticker`
+/// is called with the returned id. Must be called on the driver thread.
+pub(crate) fn add_ticker(period: Duration, tick: impl FnMut() + 'static) -> u64 {
+    debug_assert!(on_driver());
+    let id = NEXT_TICKER_ID.with(|n| n.replace(n.get() + 1));
+    TICKERS.with(|tickers| {
+        tickers.borrow_mut().push(Ticker {
+            id,
+            period,
+            next: clock::now() + period,
+            tick: Box::new(tick),
+        })
+    });
+    id
+}
+
+/// Must be called on the driver thread.
+pub(crate) fn remove_ticker(id: u64) {
+    debug_assert!(on_driver());
+    TICKERS.with(|tickers| tickers.borrow_mut().retain(|t| t.id != id));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_call_submit() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..10 {
+            let order = order.clone();
+            submit(move || order.lock().unwrap().push(i));
+        }
+        // Runs after what was submitted before, and may borrow.
+        let len = call(|| order.lock().unwrap().len());
+        assert_eq!(len, 10);
+
+        let on_driver_thread = call(|| {
+            let later = order.clone();
+            submit(move || later.lock().unwrap().push(11));
+            // Inline, before the command just submitted.
+            call(|| order.lock().unwrap().push(10));
+            on_driver()
+        });
+        assert!(on_driver_thread && !on_driver());
+        call(|| ());
+        assert_eq!(*order.lock().unwrap(), (0..12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_call_panic() {
+        let res = panic::catch_unwind(|| call(|| panic!("boom")));
+        assert!(res.is_err());
+        assert_eq!(call(|| 1), 1);
+    }
+}
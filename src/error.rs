@@ -0,0 +1,95 @@
+use std::io;
+
+use super::lwip::*;
+
+/// Errors reported by lwIP.
+///
+/// Converts into an `io::Error` of the matching kind, from which it can be
+/// recovered with `io::Error::get_ref` and `downcast_ref`.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("out of memory")]
+    OutOfMemory,
+    /// The peer reset the connection.
+    #[error("connection reset")]
+    ConnectionReset,
+    /// The connection was aborted by the stack, e.g. evicted to make room
+    /// for a new one.
+    #[error("connection aborted")]
+    ConnectionAborted,
+    /// The peer stopped answering.
+    #[error("timed out")]
+    Timeout,
+    /// Nothing was received for `NetStackBuilder::idle_timeout`.
+    #[error("idle timeout")]
+    IdleTimeout,
+    /// The connection was closed by both sides.
+    #[error("connection closed")]
+    Closed,
+    /// The connection is not established, or was shut down for writing.
+    #[error("not connected")]
+    NotConnected,
+    #[error("address in use")]
+    AddressInUse,
+    /// No netif to send to.
+    #[error("routing error")]
+    Routing,
+    /// Any other lwIP `err_t`.
+    #[error("LwIP error ({0})")]
+    LwIP(i8),
+}
+
+impl Error {
+    #[allow(non_upper_case_globals)]
+    pub(crate) fn from_err(err: err_t) -> Self {
+        match err as err_enum_t {
+            err_enum_t_ERR_MEM | err_enum_t_ERR_BUF => Error::OutOfMemory,
+            err_enum_t_ERR_RST => Error::ConnectionReset,
+            err_enum_t_ERR_ABRT => Error::ConnectionAborted,
+            err_enum_t_ERR_TIMEOUT => Error::Timeout,
+            err_enum_t_ERR_CLSD => Error::Closed,
+            err_enum_t_ERR_CONN => Error::NotConnected,
+            err_enum_t_ERR_USE => Error::AddressInUse,
+            err_enum_t_ERR_RTE => Error::Routing,
+            _ => Error::LwIP(err),
+        }
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::OutOfMemory => io::ErrorKind::OutOfMemory,
+            Error::ConnectionReset => io::ErrorKind::ConnectionReset,
+            Error::ConnectionAborted => io::ErrorKind::ConnectionAborted,
+            Error::Timeout | Error::IdleTimeout => io::ErrorKind::TimedOut,
+            Error::Closed => io::ErrorKind::BrokenPipe,
+            Error::NotConnected => io::ErrorKind::NotConnected,
+            Error::AddressInUse => io::ErrorKind::AddrInUse,
+            Error::Routing => io::ErrorKind::NetworkUnreachable,
+            Error::LwIP(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_err() {
+        let err = Error::from_err(err_enum_t_ERR_RST as err_t);
+        assert_eq!(err, Error::ConnectionReset);
+        let io_err = io::Error::from(err);
+        assert_eq!(io_err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(io_err.get_ref().unwrap().downcast_ref(), Some(&err));
+        let err = Error::from_err(err_enum_t_ERR_ARG as err_t);
+        assert_eq!(err, Error::LwIP(err_enum_t_ERR_ARG as i8));
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(Error::IdleTimeout.kind(), Error::Timeout.kind());
+    }
+}
@@ -0,0 +1,538 @@
+use std::marker::PhantomPinned;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::{io, os::raw, pin::Pin};
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use futures::StreamExt;
+use log::warn;
+
+use super::driver;
+use super::lwip::*;
+use super::util;
+use crate::Error;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP6_DEST_UNREACH: u8 = 1;
+const ICMP6_ECHO_REQUEST: u8 = 128;
+const ICMP6_ECHO_REPLY: u8 = 129;
+
+/// How a `NetStack` handles echo requests that no `IcmpSocket` takes.
+#[repr(u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpMode {
+    /// Answer echo requests from within the stack, as if every destination
+    /// were reachable.
+    #[default]
+    Reply = 0,
+    /// Silently drop echo requests.
+    Drop = 1,
+}
+
+/// Reason reported by an ICMP/ICMPv6 destination unreachable message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unreachable {
+    Network,
+    Host,
+    Port,
+    AdminProhibited,
+}
+
+impl Unreachable {
+    fn code(self, ipv4: bool) -> u8 {
+        match (self, ipv4) {
+            (Unreachable::Network, true) => 0,
+            (Unreachable::Host, true) => 1,
+            (Unreachable::Port, true) => 3,
+            (Unreachable::AdminProhibited, true) => 13,
+            (Unreachable::Network, false) => 0,
+            (Unreachable::AdminProhibited, false) => 1,
+            (Unreachable::Host, false) => 3,
+            (Unreachable::Port, false) => 4,
+        }
+    }
+}
+
+/// An ICMP or ICMPv6 echo request sent by a host on the TUN side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoRequest {
+    pub src_addr: IpAddr,
+    pub dst_addr: IpAddr,
+    pub ident: u16,
+    pub seq: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Returns the ICMP type of an IPv4/IPv6 packet handed to a raw pcb.
+///
+/// IPv6 extension headers are not followed. lwIP only hands a packet to the
+/// raw pcbs for ICMPv6 when ICMPv6 is its first next header, and answers
+/// echo requests behind extension headers itself.
+unsafe fn icmp_type(p: *const pbuf) -> u8 {
+    let first = pbuf_get_at(p, 0);
+    match first >> 4 {
+        4 => pbuf_get_at(p, ((first & 0x0f) as u16) * 4),
+        6 if pbuf_get_at(p, 6) == IP6_NEXTH_ICMP6 as u8 => pbuf_get_at(p, 40),
+        _ => 0xff,
+    }
+}
+
+fn parse_echo_request(pkt: &[u8]) -> Option<EchoRequest> {
+    let (src_addr, dst_addr, icmp) = match pkt.first()? >> 4 {
+        4 => {
+            let hlen = ((pkt[0] & 0x0f) as usize) * 4;
+            if pkt.len() < hlen + 8 || pkt[hlen] != ICMP_ECHO_REQUEST {
+                return None;
+            }
+            let src: [u8; 4] = pkt[12..16].try_into().ok()?;
+            let dst: [u8; 4] = pkt[16..20].try_into().ok()?;
+            (
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                &pkt[hlen..],
+            )
+        }
+        6 => {
+            // No extension headers, see `icmp_type`.
+            if pkt.len() < 48 || pkt[6] != IP6_NEXTH_ICMP6 as u8 || pkt[40] != ICMP6_ECHO_REQUEST {
+                return None;
+            }
+            let src: [u8; 16] = pkt[8..24].try_into().ok()?;
+            let dst: [u8; 16] = pkt[24..40].try_into().ok()?;
+            (
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                &pkt[40..],
+            )
+        }
+        _ => return None,
+    };
+    Some(EchoRequest {
+        src_addr,
+        dst_addr,
+        ident: u16::from_be_bytes([icmp[4], icmp[5]]),
+        seq: u16::from_be_bytes([icmp[6], icmp[7]]),
+        payload: icmp[8..].to_vec(),
+    })
+}
+
+/// Builds the ICMP message answering `req`, checksum included.
+fn echo_reply(req: &EchoRequest) -> Vec<u8> {
+    let icmp_type = if req.src_addr.is_ipv4() {
+        ICMP_ECHO_REPLY
+    } else {
+        ICMP6_ECHO_REPLY
+    };
+    let mut msg = Vec::with_capacity(8 + req.payload.len());
+    msg.extend_from_slice(&[icmp_type, 0, 0, 0]);
+    msg.extend_from_slice(&req.ident.to_be_bytes());
+    msg.extend_from_slice(&req.seq.to_be_bytes());
+    msg.extend_from_slice(&req.payload);
+    let cksum = util::icmp_checksum(&req.dst_addr, &req.src_addr, &msg);
+    msg[2..4].copy_from_slice(&cksum.to_be_bytes());
+    msg
+}
+
+/// Builds a destination unreachable message about a datagram sent from
+/// `src` to `dst`.
+///
+/// The offending datagram is not kept around, so the quoted IP header is
+/// rebuilt from the flow, followed by the first 8 bytes of the transport
+/// header in `l4`. That is all the sender needs to match the error to its
+/// socket.
+fn dest_unreachable(
+    reason: Unreachable,
+    proto: u8,
+    src: &SocketAddr,
+    dst: &SocketAddr,
+    l4: [u8; 8],
+) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(8 + 40 + 8);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            msg.extend_from_slice(&[ICMP_DEST_UNREACH, reason.code(true), 0, 0, 0, 0, 0, 0]);
+            let mut hdr = [0u8; 20];
+            hdr[0] = 0x45;
+            hdr[2..4].copy_from_slice(&28u16.to_be_bytes());
+            hdr[8] = IP_DEFAULT_TTL as u8;
+            hdr[9] = proto;
+            hdr[12..16].copy_from_slice(&src_ip.octets());
+            hdr[16..20].copy_from_slice(&dst_ip.octets());
+            let cksum = util::checksum(&[&hdr]);
+            hdr[10..12].copy_from_slice(&cksum.to_be_bytes());
+            msg.extend_from_slice(&hdr);
+        }
+        (src_ip, dst_ip) => {
+            msg.extend_from_slice(&[ICMP6_DEST_UNREACH, reason.code(false), 0, 0, 0, 0, 0, 0]);
+            msg.extend_from_slice(&[0x60, 0, 0, 0, 0, 8, proto, IP_DEFAULT_TTL as u8]);
+            msg.extend_from_slice(&to_ipv6(src_ip).octets());
+            msg.extend_from_slice(&to_ipv6(dst_ip).octets());
+        }
+    }
+    msg.extend_from_slice(&l4);
+    let cksum = util::icmp_checksum(&dst.ip(), &src.ip(), &msg);
+    msg[2..4].copy_from_slice(&cksum.to_be_bytes());
+    msg
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Tells `src` that its datagram to `dst` could not be delivered.
+///
+/// `l4` holds the first 8 bytes of the transport header, in network order.
+pub(crate) fn send_unreachable(
+    netif_idx: u8,
+    reason: Unreachable,
+    proto: u8,
+    src: &SocketAddr,
+    dst: &SocketAddr,
+    l4: [u8; 8],
+) -> io::Result<()> {
+    driver::call(|| unsafe {
+        output_unreachable(get_netif(netif_idx)?, reason, proto, src, dst, l4)
+    })
+}
+
+/// Same as `send_unreachable`, for callers already on the driver thread.
+pub(crate) unsafe fn output_unreachable(
+    netif: *mut netif,
+    reason: Unreachable,
+    proto: u8,
+    src: &SocketAddr,
+    dst: &SocketAddr,
+    l4: [u8; 8],
+) -> io::Result<()> {
+    let msg = dest_unreachable(reason, proto, src, dst, l4);
+    output_icmp(netif, &dst.ip(), &src.ip(), &msg)
+}
+
+/// Sends an ICMP/ICMPv6 message out of the netif with index `netif_idx`.
+pub(crate) fn send_icmp(netif_idx: u8, src: &IpAddr, dst: &IpAddr, msg: &[u8]) -> io::Result<()> {
+    driver::call(|| unsafe { output_icmp(get_netif(netif_idx)?, src, dst, msg) })
+}
+
+unsafe fn get_netif(netif_idx: u8) -> io::Result<*mut netif> {
+    let netif = netif_get_by_index(netif_idx);
+    if netif.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "netstack has been dropped",
+        ));
+    }
+    Ok(netif)
+}
+
+unsafe fn output_icmp(netif: *mut netif, src: &IpAddr, dst: &IpAddr, msg: &[u8]) -> io::Result<()> {
+    if src.is_ipv4() != dst.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "mismatched address families",
+        ));
+    }
+    let pbuf = pbuf_alloc(pbuf_layer_PBUF_IP, msg.len() as u16_t, pbuf_type_PBUF_RAM);
+    if pbuf.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "pbuf_alloc null alloc",
+        ));
+    }
+    pbuf_take(pbuf, msg.as_ptr() as *const raw::c_void, msg.len() as u16_t);
+    let src_ip = util::to_ip_addr_t(*src);
+    let dst_ip = util::to_ip_addr_t(*dst);
+    let err = if dst.is_ipv4() {
+        ip4_output_if_src(
+            pbuf,
+            &src_ip.u_addr.ip4,
+            &dst_ip.u_addr.ip4,
+            IP_DEFAULT_TTL as u8_t,
+            0,
+            IP_PROTO_ICMP as u8_t,
+            netif,
+        )
+    } else {
+        ip6_output_if_src(
+            pbuf,
+            &src_ip.u_addr.ip6,
+            &dst_ip.u_addr.ip6,
+            IP_DEFAULT_TTL as u8_t,
+            0,
+            IP6_NEXTH_ICMP6 as u8_t,
+            netif,
+        )
+    };
+    pbuf_free(pbuf);
+    if err != err_enum_t_ERR_OK as err_t {
+        return Err(Error::from_err(err).into());
+    }
+    Ok(())
+}
+
+/// Creates the ICMP and ICMPv6 raw pcbs bound to `netif`.
+///
+/// Raw pcbs are consulted newest first, a callback returning 0 passes the
+/// packet on to older pcbs and eventually to lwIP's own ICMP handling.
+pub(crate) unsafe fn new_raw_pcbs(
+    netif: usize,
+    recv: raw_recv_fn,
+    arg: *mut raw::c_void,
+) -> Result<[usize; 2], Error> {
+    let pcb4 = raw_new_ip_type(
+        lwip_ip_addr_type_IPADDR_TYPE_V4 as u8_t,
+        IP_PROTO_ICMP as u8_t,
+    );
+    let pcb6 = raw_new_ip_type(
+        lwip_ip_addr_type_IPADDR_TYPE_V6 as u8_t,
+        IP6_NEXTH_ICMP6 as u8_t,
+    );
+    if pcb4.is_null() || pcb6.is_null() {
+        remove_raw_pcbs([pcb4 as usize, pcb6 as usize]);
+        return Err(Error::OutOfMemory);
+    }
+    for pcb in [pcb4, pcb6] {
+        raw_bind_netif(pcb, netif as *const netif);
+        raw_recv(pcb, recv, arg);
+    }
+    Ok([pcb4 as usize, pcb6 as usize])
+}
+
+pub(crate) unsafe fn remove_raw_pcbs(pcbs: [usize; 2]) {
+    for pcb in pcbs {
+        if pcb != 0 {
+            raw_recv(pcb as *mut raw_pcb, None, std::ptr::null_mut());
+            raw_remove(pcb as *mut raw_pcb);
+        }
+    }
+}
+
+/// Raw pcb callback of a `NetStack`, `arg` points to its `IcmpMode`.
+pub(crate) unsafe extern "C" fn icmp_mode_recv_cb(
+    arg: *mut raw::c_void,
+    _pcb: *mut raw_pcb,
+    p: *mut pbuf,
+    _addr: *const ip_addr_t,
+) -> u8_t {
+    let mode = &*(arg as *const AtomicU8);
+    if mode.load(Ordering::Relaxed) != IcmpMode::Drop as u8 {
+        return 0;
+    }
+    match icmp_type(p) {
+        ICMP_ECHO_REQUEST | ICMP6_ECHO_REQUEST => {
+            pbuf_free(p);
+            1
+        }
+        _ => 0,
+    }
+}
+
+unsafe extern "C" fn icmp_socket_recv_cb(
+    arg: *mut raw::c_void,
+    _pcb: *mut raw_pcb,
+    p: *mut pbuf,
+    _addr: *const ip_addr_t,
+) -> u8_t {
+    if arg.is_null() {
+        warn!("icmp socket has been closed");
+        return 0;
+    }
+    match icmp_type(p) {
+        ICMP_ECHO_REQUEST | ICMP6_ECHO_REQUEST => (),
+        _ => return 0,
+    }
+    let tot_len = std::ptr::read_unaligned(p).tot_len;
+    let mut buf = Vec::with_capacity(tot_len as usize);
+    pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0);
+    buf.set_len(tot_len as usize);
+    let req = match parse_echo_request(&buf) {
+        Some(req) => req,
+        None => return 0,
+    };
+    pbuf_free(p);
+    let socket = &*(arg as *const IcmpSocket);
+    if socket.tx.lock().unwrap().try_send(req).is_err() {
+        // log::trace!("try send icmp pkt failed (netstack): {}", e);
+    }
+    1
+}
+
+/// Receives the echo requests sent into a `NetStack`, so that they can be
+/// proxied and answered with `IcmpSendHalf::send_echo_reply`.
+///
+/// While the socket is alive it takes precedence over the stack's
+/// `IcmpMode`.
+pub struct IcmpSocket {
+    pcbs: [usize; 2],
+    netif_idx: u8,
+    /// Only used by the receive callback, on the driver thread.
+    tx: Mutex<Sender<EchoRequest>>,
+    rx: Receiver<EchoRequest>,
+    _pin: PhantomPinned,
+}
+
+impl IcmpSocket {
+    pub(crate) fn new(netif: usize, buffer_size: usize) -> Result<Pin<Box<Self>>, Error> {
+        let (tx, rx): (Sender<EchoRequest>, Receiver<EchoRequest>) = channel(buffer_size);
+        let mut socket = Box::pin(Self {
+            pcbs: [0, 0],
+            netif_idx: unsafe { (*(netif as *const netif)).num } + 1,
+            tx: Mutex::new(tx),
+            rx,
+            _pin: PhantomPinned,
+        });
+        let arg = &*socket as *const IcmpSocket as usize;
+        let pcbs = driver::call(|| unsafe {
+            new_raw_pcbs(netif, Some(icmp_socket_recv_cb), arg as *mut raw::c_void)
+        })?;
+        unsafe { socket.as_mut().get_unchecked_mut() }.pcbs = pcbs;
+        Ok(socket)
+    }
+
+    pub fn split(self: Pin<Box<Self>>) -> (IcmpSendHalf, IcmpRecvHalf) {
+        (
+            IcmpSendHalf {
+                netif_idx: self.netif_idx,
+            },
+            IcmpRecvHalf { socket: self },
+        )
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        let pcbs = self.pcbs;
+        driver::call(|| unsafe { remove_raw_pcbs(pcbs) });
+    }
+}
+
+impl Stream for IcmpSocket {
+    type Item = EchoRequest;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = unsafe { self.get_unchecked_mut() };
+        this.rx.poll_next_unpin(cx)
+    }
+}
+
+pub struct IcmpSendHalf {
+    pub(crate) netif_idx: u8,
+}
+
+impl IcmpSendHalf {
+    /// Answers `request` on behalf of its destination.
+    pub fn send_echo_reply(&self, request: &EchoRequest) -> io::Result<()> {
+        let msg = echo_reply(request);
+        send_icmp(self.netif_idx, &request.dst_addr, &request.src_addr, &msg)
+    }
+}
+
+pub struct IcmpRecvHalf {
+    pub(crate) socket: Pin<Box<IcmpSocket>>,
+}
+
+impl IcmpRecvHalf {
+    pub async fn recv(&mut self) -> io::Result<EchoRequest> {
+        match self.socket.next().await {
+            Some(req) => Ok(req),
+            None => Err(io::Error::other("recv icmp socket failed: tx closed")),
+        }
+    }
+}
+
+impl Stream for IcmpRecvHalf {
+    type Item = EchoRequest;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.socket).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_echo_reply_roundtrip() {
+        let mut pkt = vec![
+            0x45, 0, 0, 32, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1, 1, 1, 1, 1,
+        ];
+        pkt.extend_from_slice(&[
+            ICMP_ECHO_REQUEST,
+            0,
+            0,
+            0,
+            0,
+            7,
+            0,
+            1,
+            0xab,
+            0xcd,
+            0xef,
+            0x01,
+        ]);
+        let req = parse_echo_request(&pkt).unwrap();
+        assert_eq!(req.src_addr, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(req.dst_addr, IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)));
+        assert_eq!((req.ident, req.seq), (7, 1));
+        assert_eq!(req.payload, vec![0xab, 0xcd, 0xef, 0x01]);
+        let reply = echo_reply(&req);
+        assert_eq!(reply[0], ICMP_ECHO_REPLY);
+        assert_eq!(util::icmp_checksum(&req.dst_addr, &req.src_addr, &reply), 0);
+    }
+
+    #[test]
+    fn test_parse_echo_request_ipv6() {
+        let mut pkt = vec![0x60, 0, 0, 0, 0, 12, 58, 64];
+        pkt.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).octets());
+        pkt.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        pkt.extend_from_slice(&[ICMP6_ECHO_REQUEST, 0, 0, 0, 0, 7, 0, 1, 1, 2, 3, 4]);
+        let req = parse_echo_request(&pkt).unwrap();
+        assert_eq!((req.ident, req.seq), (7, 1));
+        assert_eq!(req.payload, vec![1, 2, 3, 4]);
+        // The same behind a hop-by-hop options header.
+        pkt[6] = 0;
+        let mut hbh = vec![58, 0, 1, 4, 0, 0, 0, 0];
+        hbh.extend_from_slice(&pkt[40..]);
+        pkt.truncate(40);
+        pkt.extend_from_slice(&hbh);
+        assert_eq!(parse_echo_request(&pkt), None);
+    }
+
+    #[test]
+    fn test_dest_unreachable() {
+        let src: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let dst: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let msg = dest_unreachable(
+            Unreachable::Port,
+            17,
+            &src,
+            &dst,
+            [0x14, 0xe9, 0, 53, 0, 8, 0, 0],
+        );
+        assert_eq!(&msg[..2], &[ICMP_DEST_UNREACH, 3]);
+        assert_eq!(msg.len(), 8 + 20 + 8);
+        assert_eq!(util::checksum(&[&msg[8..28]]), 0);
+        assert_eq!(util::icmp_checksum(&dst.ip(), &src.ip(), &msg), 0);
+
+        let src: SocketAddr = "[fd00::1]:5353".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let msg = dest_unreachable(
+            Unreachable::Port,
+            17,
+            &src,
+            &dst,
+            [0x14, 0xe9, 0, 53, 0, 8, 0, 0],
+        );
+        assert_eq!(&msg[..2], &[ICMP6_DEST_UNREACH, 4]);
+        assert_eq!(msg.len(), 8 + 40 + 8);
+        assert_eq!(util::icmp_checksum(&dst.ip(), &src.ip(), &msg), 0);
+    }
+}
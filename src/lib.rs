@@ -1,23 +1,38 @@
+mod builder;
+mod capture;
+mod clock;
+mod connections;
+mod driver;
+mod error;
+mod icmp;
 mod lwip;
-mod mutex;
 mod output;
+mod pbuf;
+mod queue;
+mod reaper;
+mod sans_io;
 mod stack;
+mod stats;
 mod tcp_listener;
 mod tcp_stream;
 mod tcp_stream_context;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 pub mod udp;
 mod util;
+mod vnet;
 
-pub(crate) static LWIP_MUTEX: mutex::AtomicMutex = mutex::AtomicMutex::new();
-pub(crate) use mutex::AtomicMutexGuard as LWIPMutexGuard;
-
+pub use builder::NetStackBuilder;
+pub use capture::{Capture, Direction};
+pub use clock::{Clock, VirtualClock};
+pub use connections::{ConnectionInfo, TcpState};
+pub use error::Error;
+pub use icmp::{EchoRequest, IcmpMode, IcmpRecvHalf, IcmpSendHalf, IcmpSocket, Unreachable};
+pub use queue::{DropCounter, OverflowPolicy};
+pub use reaper::Eviction;
+pub use sans_io::{SyncNetStack, TcpHandle};
 pub use stack::NetStack;
-pub use tcp_listener::TcpListener;
+pub use stats::{LwipStats, PoolStats, ProtoStats, Stats, StatsHandle, TcpPcbStats};
+pub use tcp_listener::{SynRequest, TcpListener};
 pub use tcp_stream::TcpStream;
-pub use udp::UdpSocket;
-
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error("LwIP error ({0})")]
-    LwIP(i8),
-}
+pub use udp::{UdpFlow, UdpListener, UdpSocket};
@@ -374,6 +374,14 @@ icmp_send_response(struct pbuf *p, u8_t type, u8_t code)
   icmphdr->seqno = 0;
 
   /* copy fields from original packet */
+#if TUN2SOCKS
+  /* the original packet may be shorter than that, e.g. a first fragment
+     without data, send what there is */
+  if (p->tot_len < IP_HLEN + ICMP_DEST_UNREACH_DATASIZE) {
+    pbuf_copy_partial(p, (u8_t *)q->payload + sizeof(struct icmp_echo_hdr), p->tot_len, 0);
+    pbuf_realloc(q, (u16_t)(sizeof(struct icmp_echo_hdr) + p->tot_len));
+  } else
+#endif /* TUN2SOCKS */
   SMEMCPY((u8_t *)q->payload + sizeof(struct icmp_echo_hdr), (u8_t *)p->payload,
           IP_HLEN + ICMP_DEST_UNREACH_DATASIZE);
 
@@ -153,8 +153,11 @@ ip4_route(const ip4_addr_t *dest)
 {
 #if TUN2SOCKS
   // go-tun2socks logic
-  // no routing and just use the default netif, netif_list[0], i.e., the loopif
-  // enable loopif by setting LWIP_HAVE_LOOPIF = 1 in lwipopts.h
+  // no routing, answer on the netif the current packet came in from, or
+  // fall back to the first one in netif_list
+  if (ip_current_input_netif() != NULL) {
+    return ip_current_input_netif();
+  }
   return netif_list;
 #endif /* TUN2SOCKS */
 
@@ -454,6 +457,19 @@ ip4_input(struct pbuf *p, struct netif *inp)
   IP_STATS_INC(ip.recv);
   MIB2_STATS_INC(mib2.ipinreceives);
 
+#if TUN2SOCKS
+  /* packets come straight from the TUN side, without any link layer
+     padding, the header fields read below may not be there */
+  if (p->len < IP_HLEN) {
+    LWIP_DEBUGF(IP_DEBUG | LWIP_DBG_LEVEL_SERIOUS, ("ip4_input: short packet (%"U16_F" bytes) received, IP packet dropped\n", p->len));
+    pbuf_free(p);
+    IP_STATS_INC(ip.lenerr);
+    IP_STATS_INC(ip.drop);
+    MIB2_STATS_INC(mib2.ipindiscards);
+    return ERR_OK;
+  }
+#endif /* TUN2SOCKS */
+
   /* identify the IP header */
   iphdr = (struct ip_hdr *)p->payload;
   if (IPH_V(iphdr) != 4) {
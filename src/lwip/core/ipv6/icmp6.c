@@ -405,6 +405,14 @@ icmp6_send_response_with_addrs_and_netif(struct pbuf *p, u8_t code, u32_t data,
   icmp6hdr->data = lwip_htonl(data);
 
   /* copy fields from original packet */
+#if TUN2SOCKS
+  /* the original packet may be shorter than that, e.g. an IPv6 header
+     alone, send what there is */
+  if (p->tot_len < IP6_HLEN + LWIP_ICMP6_DATASIZE) {
+    pbuf_copy_partial(p, (u8_t *)q->payload + sizeof(struct icmp6_hdr), p->tot_len, 0);
+    pbuf_realloc(q, (u16_t)(sizeof(struct icmp6_hdr) + p->tot_len));
+  } else
+#endif /* TUN2SOCKS */
   SMEMCPY((u8_t *)q->payload + sizeof(struct icmp6_hdr), (u8_t *)p->payload,
           IP6_HLEN + LWIP_ICMP6_DATASIZE);
 
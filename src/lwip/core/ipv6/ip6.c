@@ -87,8 +87,11 @@ ip6_route(const ip6_addr_t *src, const ip6_addr_t *dest)
 {
 #if TUN2SOCKS
   // go-tun2socks logic
-  // no routing and just use the default netif, netif_list[0], i.e., the loopif
-  // enable loopif by setting LWIP_HAVE_LOOPIF = 1 in lwipopts.h
+  // no routing, answer on the netif the current packet came in from, or
+  // fall back to the first one in netif_list
+  if (ip_current_input_netif() != NULL) {
+    return ip_current_input_netif();
+  }
   return netif_list;
 #endif /* TUN2SOCKS */
 
@@ -537,6 +540,18 @@ ip6_input(struct pbuf *p, struct netif *inp)
 
   IP6_STATS_INC(ip6.recv);
 
+#if TUN2SOCKS
+  /* the version is read as part of the first 32-bit word, which a short
+     packet from the TUN side may not even have */
+  if (p->len < IP6_HLEN) {
+    LWIP_DEBUGF(IP6_DEBUG | LWIP_DBG_LEVEL_SERIOUS, ("ip6_input: short packet (%"U16_F" bytes) received, IP packet dropped\n", p->len));
+    pbuf_free(p);
+    IP6_STATS_INC(ip6.lenerr);
+    IP6_STATS_INC(ip6.drop);
+    return ERR_OK;
+  }
+#endif /* TUN2SOCKS */
+
   /* identify the IP header */
   ip6hdr = (struct ip6_hdr *)p->payload;
   if (IP6H_V(ip6hdr) != 6) {
@@ -722,6 +737,19 @@ netif_found:
   /* Process known option extension headers, if present. */
   while (*nexth != IP6_NEXTH_NONE)
   {
+#if TUN2SOCKS
+    /* the length of these headers is read before being checked against
+       the packet, make sure their first 8 bytes are there */
+    if (((*nexth == IP6_NEXTH_HOPBYHOP) || (*nexth == IP6_NEXTH_DESTOPTS) ||
+         (*nexth == IP6_NEXTH_ROUTING)) && (p->len < 8)) {
+      LWIP_DEBUGF(IP6_DEBUG | LWIP_DBG_LEVEL_SERIOUS,
+        ("IPv6 extension header does not fit in first pbuf (len %"U16_F"), IPv6 packet dropped.\n", p->len));
+      pbuf_free(p);
+      IP6_STATS_INC(ip6.lenerr);
+      IP6_STATS_INC(ip6.drop);
+      goto ip6_input_cleanup;
+    }
+#endif /* TUN2SOCKS */
     switch (*nexth) {
     case IP6_NEXTH_HOPBYHOP:
     {
@@ -759,6 +787,13 @@ netif_found:
         s32_t opt_dlen = 0;
 
         opt_hdr = (struct ip6_opt_hdr *)((u8_t *)hbh_hdr + opt_offset);
+#if TUN2SOCKS
+        /* all options but Pad1 have a length byte, which may be past the
+           end of the header, and of the packet */
+        if ((IP6_OPT_TYPE(opt_hdr) != IP6_PAD1_OPTION) && (opt_offset + 1 >= hlen)) {
+          break;
+        }
+#endif /* TUN2SOCKS */
 
         switch (IP6_OPT_TYPE(opt_hdr)) {
         /* @todo: process IPV6 Hop-by-Hop option data */
@@ -848,6 +883,13 @@ netif_found:
         s32_t opt_dlen = 0;
 
         opt_hdr = (struct ip6_opt_hdr *)((u8_t *)dest_hdr + opt_offset);
+#if TUN2SOCKS
+        /* all options but Pad1 have a length byte, which may be past the
+           end of the header, and of the packet */
+        if ((IP6_OPT_TYPE(opt_hdr) != IP6_PAD1_OPTION) && (opt_offset + 1 >= hlen)) {
+          break;
+        }
+#endif /* TUN2SOCKS */
 
         switch (IP6_OPT_TYPE(opt_hdr))
         {
@@ -889,7 +889,7 @@ tcp_listen_with_backlog_and_err(struct tcp_pcb *pcb, u8_t backlog, err_t *err)
   lpcb->state = LISTEN;
   lpcb->prio = pcb->prio;
   lpcb->so_options = pcb->so_options;
-  lpcb->netif_idx = NETIF_NO_INDEX;
+  lpcb->netif_idx = pcb->netif_idx;
   lpcb->ttl = pcb->ttl;
   lpcb->tos = pcb->tos;
 #if LWIP_IPV4 && LWIP_IPV6
@@ -1406,7 +1406,12 @@ tcp_slowtmr_start:
       tcp_free(pcb2);
 
       tcp_active_pcbs_changed = 0;
+#if TUN2SOCKS
+      // tell timeouts apart from tcp_abort(), see tcp_err_cb
+      TCP_EVENT_ERR(last_state, err_fn, err_arg, ERR_TIMEOUT);
+#else
       TCP_EVENT_ERR(last_state, err_fn, err_arg, ERR_ABRT);
+#endif /* TUN2SOCKS */
       if (tcp_active_pcbs_changed) {
         goto tcp_slowtmr_start;
       }
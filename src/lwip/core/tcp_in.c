@@ -318,8 +318,13 @@ tcp_input(struct pbuf *p, struct netif *inp)
     for (lpcb = tcp_listen_pcbs.listen_pcbs; lpcb != NULL; lpcb = lpcb->next) {
 #if TUN2SOCKS
       // go-tun2socks logic
-      // use the first one, and it should be set to the loopif
-      break;
+      // use the first one bound to the input netif, or to no netif at all
+      if ((lpcb->netif_idx == NETIF_NO_INDEX) ||
+          (lpcb->netif_idx == netif_get_index(ip_data.current_input_netif))) {
+        break;
+      }
+      prev = (struct tcp_pcb *)lpcb;
+      continue;
 #endif /* TUN2SOCKS */
 
       /* check if PCB is bound to specific netif */
@@ -657,7 +662,12 @@ tcp_listen_input(struct tcp_pcb_listen *pcb)
   } else if (flags & TCP_SYN) {
     LWIP_DEBUGF(TCP_DEBUG, ("TCP connection request %"U16_F" -> %"U16_F".\n", tcphdr->src, tcphdr->dest));
 #if TCP_LISTEN_BACKLOG
+#if TUN2SOCKS
+    /* a backlog of 0 sets no limit */
+    if ((pcb->backlog != 0) && (pcb->accepts_pending >= pcb->backlog)) {
+#else /* TUN2SOCKS */
     if (pcb->accepts_pending >= pcb->backlog) {
+#endif /* TUN2SOCKS */
       LWIP_DEBUGF(TCP_DEBUG, ("tcp_listen_input: listen backlog exceeded for port %"U16_F"\n", tcphdr->dest));
       return;
     }
@@ -675,8 +685,15 @@ tcp_listen_input(struct tcp_pcb_listen *pcb)
       return;
     }
 #if TCP_LISTEN_BACKLOG
-    pcb->accepts_pending++;
-    tcp_set_flags(npcb, TF_BACKLOGPEND);
+#if TUN2SOCKS
+    /* nothing to count without a limit, which also keeps the counter
+       from wrapping around */
+    if (pcb->backlog != 0)
+#endif /* TUN2SOCKS */
+    {
+      pcb->accepts_pending++;
+      tcp_set_flags(npcb, TF_BACKLOGPEND);
+    }
 #endif /* TCP_LISTEN_BACKLOG */
     /* Set up the new PCB. */
     ip_addr_copy(npcb->local_ip, *ip_current_dest_addr());
@@ -1936,6 +1953,14 @@ tcp_parseopt(struct tcp_pcb *pcb)
   if (tcphdr_optlen != 0) {
     for (tcp_optidx = 0; tcp_optidx < tcphdr_optlen; ) {
       u8_t opt = tcp_get_next_optbyte();
+#if TUN2SOCKS
+      /* all options but EOL and NOP have a length byte, which may be past
+         the end of the segment if the options end with the kind byte */
+      if ((opt != LWIP_TCP_OPT_EOL) && (opt != LWIP_TCP_OPT_NOP) && (tcp_optidx >= tcphdr_optlen)) {
+        LWIP_DEBUGF(TCP_INPUT_DEBUG, ("tcp_parseopt: bad length\n"));
+        return;
+      }
+#endif /* TUN2SOCKS */
       switch (opt) {
         case LWIP_TCP_OPT_EOL:
           /* End of options. */
@@ -93,7 +93,24 @@
 
 /* Define some copy-macros for checksum-on-copy so that the code looks
    nicer by preventing too many ifdef's. */
-#if TCP_CHECKSUM_ON_COPY
+#if TCP_CHECKSUM_ON_COPY && TUN2SOCKS && LWIP_CHECKSUM_CTRL_PER_NETIF
+/* Data sent on a netif leaving the TCP checksum to the device is only
+   copied, 'chksum_on_copy' is set by tcp_write. */
+#define TCP_DATA_COPY(dst, src, len, seg) do { \
+  if (chksum_on_copy) { \
+    tcp_seg_add_chksum(LWIP_CHKSUM_COPY(dst, src, len), \
+                       len, &seg->chksum, &seg->chksum_swapped); \
+    seg->flags |= TF_SEG_DATA_CHECKSUMMED; \
+  } else { \
+    MEMCPY(dst, src, len); \
+  } } while(0)
+#define TCP_DATA_COPY2(dst, src, len, chksum, chksum_swapped) do { \
+  if (chksum_on_copy) { \
+    tcp_seg_add_chksum(LWIP_CHKSUM_COPY(dst, src, len), len, chksum, chksum_swapped); \
+  } else { \
+    MEMCPY(dst, src, len); \
+  } } while(0)
+#elif TCP_CHECKSUM_ON_COPY
 #define TCP_DATA_COPY(dst, src, len, seg) do { \
   tcp_seg_add_chksum(LWIP_CHKSUM_COPY(dst, src, len), \
                      len, &seg->chksum, &seg->chksum_swapped); \
@@ -408,6 +425,11 @@ tcp_write(struct tcp_pcb *pcb, const void *arg, u16_t len, u8_t apiflags)
   u8_t concat_chksum_swapped = 0;
   u16_t concat_chksummed = 0;
 #endif /* TCP_CHECKSUM_ON_COPY */
+#if TCP_CHECKSUM_ON_COPY && TUN2SOCKS && LWIP_CHECKSUM_CTRL_PER_NETIF
+  struct netif *chksum_netif = netif_get_by_index(pcb->netif_idx);
+  u8_t chksum_on_copy = (chksum_netif == NULL) ||
+                        ((chksum_netif->chksum_flags & NETIF_CHECKSUM_GEN_TCP) != 0);
+#endif /* TCP_CHECKSUM_ON_COPY && TUN2SOCKS && LWIP_CHECKSUM_CTRL_PER_NETIF */
   err_t err;
   u16_t mss_local;
 
@@ -0,0 +1,17 @@
+#ifndef LWIP_HOOKS_H
+#define LWIP_HOOKS_H
+
+#include "lwip/arch.h"
+
+struct tcp_pcb;
+struct tcp_hdr;
+struct pbuf;
+
+/* Implemented in tcp_listener.rs, runs the pre-accept filter of a listener. */
+s8_t netstack_tcp_inpacket_hook(struct tcp_pcb *pcb, struct tcp_hdr *hdr, u16_t optlen,
+                                u16_t opt1len, u8_t *opt2, struct pbuf *p);
+
+#define LWIP_HOOK_TCP_INPACKET_PCB(pcb, hdr, optlen, opt1len, opt2, p) \
+    netstack_tcp_inpacket_hook(pcb, hdr, optlen, opt1len, opt2, p)
+
+#endif
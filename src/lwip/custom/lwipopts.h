@@ -49,7 +49,7 @@
 #define LWIP_ARP 0
 #define ARP_QUEUEING 0
 #define IP_FORWARD 0
-#define LWIP_ICMP 0
+#define LWIP_ICMP 1
 #define LWIP_RAW 1
 #define LWIP_DHCP 0
 #define LWIP_AUTOIP 0
@@ -70,12 +70,13 @@
 #define LWIP_IPV6 1
 #define LWIP_IPV6_MLD 0
 #define LWIP_IPV6_AUTOCONFIG 1
+// per-pcb keepalive idle, interval and count, see TcpStream::set_keepalive
+#define LWIP_TCP_KEEPALIVE 1
 
 #if defined __APPLE__
 #include <TargetConditionals.h>
 
 #if TARGET_OS_IPHONE
-#define LWIP_TCP_KEEPALIVE 1
 #define MEMP_NUM_TCP_PCB 256
 #else
 #define MEMP_NUM_TCP_PCB 1024
@@ -96,19 +97,29 @@
 #define MEMP_NUM_TCP_PCB 1024
 #endif
 
-// disable checksum checks
-#define CHECKSUM_CHECK_IP 0
-#define CHECKSUM_CHECK_UDP 0
-#define CHECKSUM_CHECK_TCP 0
-#define CHECKSUM_CHECK_ICMP 0
-#define CHECKSUM_CHECK_ICMP6 0
+// checksums are only checked on the netifs of stacks asking for it, see
+// NetStackBuilder::checksum_validation
+#define LWIP_CHECKSUM_CTRL_PER_NETIF 1
+#define CHECKSUM_CHECK_IP 1
+#define CHECKSUM_CHECK_UDP 1
+#define CHECKSUM_CHECK_TCP 1
+#define CHECKSUM_CHECK_ICMP 1
+#define CHECKSUM_CHECK_ICMP6 1
 
 #define LWIP_CHECKSUM_ON_COPY 1
 #define LWIP_CHKSUM_ALGORITHM 3
 
 #define TCP_MSS 1460
-#define TCP_WND (32 * TCP_MSS)
+// window scaling lets TCP_WND go past 64KB, up to 0xFFFF << TCP_RCV_SCALE
+#define LWIP_WND_SCALE 1
+#define TCP_RCV_SCALE 3
+#define TCP_WND (256 * 1024)
 #define TCP_SND_BUF (16 * TCP_MSS)
+// room for NetStackBuilder::send_buffer above TCP_SND_BUF
+#define TCP_SND_QUEUELEN 1024
+#define LWIP_TCP_SACK_OUT 1
+// enforce the backlog set by NetStackBuilder::backlog
+#define TCP_LISTEN_BACKLOG 1
 
 #if defined __APPLE__
 #include <TargetConditionals.h>
@@ -122,6 +133,8 @@
 #endif
 
 #define MEMP_NUM_TCP_SEG 4096
+// two ICMP raw pcbs per NetStack and per IcmpSocket
+#define MEMP_NUM_RAW_PCB 64
 #define PBUF_POOL_SIZE 512
 
 // #define TCP_MSS 1460
@@ -162,7 +175,12 @@
 #define TCPIP_DEBUG LWIP_DBG_OFF
 #define IP6_DEBUG LWIP_DBG_OFF
 
-#define LWIP_STATS 0
+// SYN filtering on listening pcbs
+#define LWIP_HOOK_FILENAME "lwiphooks.h"
+
+// counters behind NetStack::stats()
+#define LWIP_STATS 1
+#define LWIP_STATS_LARGE 1
 #define LWIP_STATS_DISPLAY 0
 #define LWIP_PERF 0
 
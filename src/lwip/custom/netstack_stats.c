@@ -0,0 +1,57 @@
+#include "lwip/opt.h"
+#include "lwip/stats.h"
+#include "lwip/memp.h"
+
+#include "netstack_stats.h"
+
+static void
+copy_proto(struct netstack_proto_stats *out, const struct stats_proto *in)
+{
+  out->xmit = in->xmit;
+  out->recv = in->recv;
+  out->drop = in->drop;
+  out->err = in->chkerr + in->lenerr + in->memerr + in->rterr +
+             in->proterr + in->opterr + in->err;
+}
+
+static void
+copy_mem(struct netstack_mem_stats *out, const struct stats_mem *in)
+{
+  out->avail = (u32_t)in->avail;
+  out->used = (u32_t)in->used;
+  out->max = (u32_t)in->max;
+  out->err = in->err;
+}
+
+void
+netstack_get_lwip_stats(struct netstack_lwip_stats *out)
+{
+  copy_proto(&out->ip4, &lwip_stats.ip);
+  copy_proto(&out->ip6, &lwip_stats.ip6);
+  copy_proto(&out->icmp, &lwip_stats.icmp);
+  copy_proto(&out->icmp6, &lwip_stats.icmp6);
+  copy_proto(&out->tcp, &lwip_stats.tcp);
+  copy_proto(&out->udp, &lwip_stats.udp);
+  copy_mem(&out->heap, &lwip_stats.mem);
+  copy_mem(&out->pbuf_pool, lwip_stats.memp[MEMP_PBUF_POOL]);
+  copy_mem(&out->pbuf, lwip_stats.memp[MEMP_PBUF]);
+  copy_mem(&out->raw_pcb, lwip_stats.memp[MEMP_RAW_PCB]);
+  copy_mem(&out->udp_pcb, lwip_stats.memp[MEMP_UDP_PCB]);
+  copy_mem(&out->tcp_pcb, lwip_stats.memp[MEMP_TCP_PCB]);
+  copy_mem(&out->tcp_pcb_listen, lwip_stats.memp[MEMP_TCP_PCB_LISTEN]);
+  copy_mem(&out->tcp_seg, lwip_stats.memp[MEMP_TCP_SEG]);
+}
+
+u32_t
+netstack_chkerr(void)
+{
+  return lwip_stats.ip.chkerr + lwip_stats.icmp.chkerr + lwip_stats.icmp6.chkerr +
+         lwip_stats.tcp.chkerr + lwip_stats.udp.chkerr;
+}
+
+u8_t
+netstack_tcp_pcb_pool_full(void)
+{
+  const struct stats_mem *s = lwip_stats.memp[MEMP_TCP_PCB];
+  return s->used >= s->avail;
+}
@@ -0,0 +1,47 @@
+#ifndef NETSTACK_STATS_H
+#define NETSTACK_STATS_H
+
+#include "lwip/arch.h"
+
+struct netstack_proto_stats {
+  u32_t xmit;
+  u32_t recv;
+  u32_t drop;
+  /* all error counters summed up */
+  u32_t err;
+};
+
+struct netstack_mem_stats {
+  u32_t avail;
+  u32_t used;
+  u32_t max;
+  u32_t err;
+};
+
+/** Flat copy of lwip_stats, so that the Rust side does not depend on its layout. */
+struct netstack_lwip_stats {
+  struct netstack_proto_stats ip4;
+  struct netstack_proto_stats ip6;
+  struct netstack_proto_stats icmp;
+  struct netstack_proto_stats icmp6;
+  struct netstack_proto_stats tcp;
+  struct netstack_proto_stats udp;
+  struct netstack_mem_stats heap;
+  struct netstack_mem_stats pbuf_pool;
+  struct netstack_mem_stats pbuf;
+  struct netstack_mem_stats raw_pcb;
+  struct netstack_mem_stats udp_pcb;
+  struct netstack_mem_stats tcp_pcb;
+  struct netstack_mem_stats tcp_pcb_listen;
+  struct netstack_mem_stats tcp_seg;
+};
+
+void netstack_get_lwip_stats(struct netstack_lwip_stats *out);
+
+/** Packets dropped for a bad checksum, by every protocol. */
+u32_t netstack_chkerr(void);
+
+/** Whether every tcp_pcb of the pool is in use. */
+u8_t netstack_tcp_pcb_pool_full(void);
+
+#endif
@@ -89,11 +89,13 @@
     return (u32_t)sys_get_ms_longlong();
   }
   
+#if !TUN2SOCKS
   u32_t
   sys_now(void)
   {
     return (u32_t)sys_get_ms_longlong();
   }
+#endif /* !TUN2SOCKS */
   
   CRITICAL_SECTION critSec;
   #if LWIP_WIN32_SYS_ARCH_ENABLE_PROTECT_COUNTER
@@ -187,7 +189,7 @@
     vprintf(format, ap);
     va_end(ap);
   }
-#else
+#elif !TUN2SOCKS
   #include <sys/time.h>
   u32_t sys_now(void)
   {
@@ -196,3 +198,5 @@
       return te.tv_sec*1000LL + te.tv_usec/1000;
   }
 #endif
+/* With TUN2SOCKS, sys_now() is implemented in Rust, reading the clock set on
+ * the NetStack (src/clock.rs). */
@@ -1,20 +1,28 @@
+use std::os::raw;
+
 use super::lwip::*;
+use super::pbuf::copy_from_pbuf;
+use super::vnet;
 use super::NetStack;
 
-pub static mut OUTPUT_CB_PTR: usize = 0x0;
-
-fn output(_netif: *mut netif, p: *mut pbuf) -> err_t {
+fn output(netif: *mut netif, p: *mut pbuf) -> err_t {
     unsafe {
-        let pbuflen = std::ptr::read_unaligned(p).tot_len;
-        let mut buf = Vec::with_capacity(pbuflen as usize);
-        pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, pbuflen, 0);
-        buf.set_len(pbuflen as usize);
-        if OUTPUT_CB_PTR == 0x0 {
+        let state = (*netif).state;
+        if state.is_null() {
             return err_enum_t_ERR_ABRT as err_t;
         }
-        let stack = &mut *(OUTPUT_CB_PTR as *mut NetStack);
-        stack.output(buf);
-        err_enum_t_ERR_OK as err_t
+        let stack = &*(state as *const NetStack);
+        // lwIP keeps TCP segments for retransmission and rewrites their
+        // headers in place, so the packet can't be shared with the user.
+        let buf = if stack.vnet_hdr() {
+            vnet::copy_from_pbuf(p)
+        } else {
+            copy_from_pbuf(p)
+        };
+        match stack.output(buf.into()) {
+            Ok(()) => err_enum_t_ERR_OK as err_t,
+            Err(_) => err_enum_t_ERR_MEM as err_t,
+        }
     }
 }
 
@@ -28,3 +36,13 @@ pub extern "C" fn output_ip4(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip4
 pub extern "C" fn output_ip6(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip6_addr_t) -> err_t {
     output(netif, p)
 }
+
+/// Sets up a netif added by a `NetStack`, packets routed to it are handed
+/// to the stack found in `netif->state`.
+pub unsafe extern "C" fn init_netif(netif: *mut netif) -> err_t {
+    (*netif).output = Some(output_ip4);
+    (*netif).output_ip6 = Some(output_ip6);
+    (*netif).mtu = 1500;
+    (*netif).name = [b't' as raw::c_char, b'n' as raw::c_char];
+    err_enum_t_ERR_OK as err_t
+}
@@ -0,0 +1,97 @@
+use std::os::raw;
+
+use bytes::{Bytes, BytesMut};
+
+use super::lwip::*;
+
+/// A `PBUF_REF` pbuf whose payload lives in a `BytesMut` owned by the pbuf.
+#[repr(C)]
+struct BytesPbuf {
+    custom: pbuf_custom,
+    buf: BytesMut,
+}
+
+unsafe extern "C" fn free_bytes_pbuf(p: *mut pbuf) {
+    drop(Box::from_raw(p as *mut BytesPbuf));
+}
+
+/// Hands a packet to lwIP, on the driver thread.
+///
+/// lwIP rewrites headers in place, so the packet is only referenced without
+/// copying when nothing else shares its memory. Returns null if out of
+/// memory.
+pub(crate) unsafe fn pbuf_from_bytes(pkt: Bytes) -> *mut pbuf {
+    let len = pkt.len() as u16_t;
+    match pkt.try_into_mut() {
+        Ok(buf) if buf.as_ptr().align_offset(4) == 0 => {
+            let bp = Box::into_raw(Box::new(BytesPbuf {
+                custom: std::mem::zeroed(),
+                buf,
+            }));
+            (*bp).custom.custom_free_function = Some(free_bytes_pbuf);
+            let p = pbuf_alloced_custom(
+                pbuf_layer_PBUF_RAW,
+                len,
+                pbuf_type_PBUF_REF,
+                &mut (*bp).custom,
+                (*bp).buf.as_mut_ptr() as *mut raw::c_void,
+                len,
+            );
+            if p.is_null() {
+                drop(Box::from_raw(bp));
+            }
+            p
+        }
+        Ok(buf) => copy_to_pbuf(&buf),
+        Err(pkt) => copy_to_pbuf(&pkt),
+    }
+}
+
+unsafe fn copy_to_pbuf(pkt: &[u8]) -> *mut pbuf {
+    let p = pbuf_alloc(pbuf_layer_PBUF_RAW, pkt.len() as u16_t, pbuf_type_PBUF_RAM);
+    if !p.is_null() {
+        pbuf_take(p, pkt.as_ptr() as *const raw::c_void, pkt.len() as u16_t);
+    }
+    p
+}
+
+/// Takes the payload out of a pbuf about to be freed, without copying if it
+/// was created by `pbuf_from_bytes` and is not referenced elsewhere.
+pub(crate) unsafe fn pbuf_to_bytes(p: *mut pbuf) -> Bytes {
+    let pv = std::ptr::read_unaligned(p);
+    if pv.next.is_null() && pv.ref_ == 1 && pv.flags & PBUF_FLAG_IS_CUSTOM as u8_t != 0 {
+        let bp = p as *mut BytesPbuf;
+        let free_fn = (*bp).custom.custom_free_function.map(|f| f as usize);
+        if free_fn == Some(free_bytes_pbuf as unsafe extern "C" fn(_) as usize) {
+            let buf = &mut (*bp).buf;
+            let offset = (pv.payload as usize).wrapping_sub(buf.as_ptr() as usize);
+            if offset <= buf.len() && pv.len as usize <= buf.len() - offset {
+                // The pbuf keeps the headers, the payload is moved out.
+                let mut payload = buf.split_off(offset);
+                payload.truncate(pv.len as usize);
+                return payload.freeze();
+            }
+        }
+    }
+    copy_from_pbuf(p).into()
+}
+
+/// Copies the content of a pbuf chain.
+pub(crate) unsafe fn copy_from_pbuf(p: *mut pbuf) -> Vec<u8> {
+    copy_from_pbuf_at(p, 0)
+}
+
+/// Copies the content of a pbuf chain behind `offset` zeroed bytes.
+pub(crate) unsafe fn copy_from_pbuf_at(p: *mut pbuf, offset: usize) -> Vec<u8> {
+    let pbuflen = std::ptr::read_unaligned(p).tot_len;
+    let mut buf = vec![0; offset];
+    buf.reserve_exact(pbuflen as usize);
+    pbuf_copy_partial(
+        p,
+        buf.as_mut_ptr().add(offset) as *mut raw::c_void,
+        pbuflen,
+        0,
+    );
+    buf.set_len(offset + pbuflen as usize);
+    buf
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::task::{Context, Poll, Waker};
+
+/// What a bounded packet queue does with a packet arriving while it is full.
+#[repr(u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the arriving packet.
+    #[default]
+    DropNewest = 0,
+    /// Drop the oldest queued packet to make room for the arriving one.
+    DropOldest = 1,
+    /// Refuse the packet and let the producer retry. On the stack output
+    /// queue, lwIP gets `ERR_MEM` and resends TCP segments once there is
+    /// room again. Queues fed by lwIP without any way to retry, like the
+    /// receive queue of a `UdpSocket`, fall back to `DropNewest`.
+    Backpressure = 2,
+}
+
+impl OverflowPolicy {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => OverflowPolicy::DropOldest,
+            2 => OverflowPolicy::Backpressure,
+            _ => OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+/// Number of packets discarded by a queue because it was full.
+///
+/// Cheap to clone, and keeps counting after the queue owner has been split
+/// or moved into a task.
+#[derive(Debug, Clone, Default)]
+pub struct DropCounter(Arc<AtomicU64>);
+
+impl DropCounter {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Inner<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+    // A packet was refused, the producer wants to hear about free room.
+    stalled: bool,
+}
+
+/// A bounded queue between lwIP callbacks and a single consumer.
+pub(crate) struct PacketQueue<T> {
+    inner: Mutex<Inner<T>>,
+    capacity: usize,
+    policy: AtomicU8,
+    drops: DropCounter,
+}
+
+impl<T> PacketQueue<T> {
+    pub fn new(capacity: usize, drops: DropCounter) -> Self {
+        let capacity = capacity.max(1);
+        PacketQueue {
+            inner: Mutex::new(Inner {
+                items: VecDeque::with_capacity(capacity),
+                waker: None,
+                stalled: false,
+            }),
+            capacity,
+            policy: AtomicU8::new(OverflowPolicy::default() as u8),
+            drops,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        OverflowPolicy::from_u8(self.policy.load(Ordering::Relaxed))
+    }
+
+    pub fn set_policy(&self, policy: OverflowPolicy) {
+        self.policy.store(policy as u8, Ordering::Relaxed);
+    }
+
+    pub fn drops(&self) -> &DropCounter {
+        &self.drops
+    }
+
+    /// Queues `item`, or hands it back if the queue is full and the policy
+    /// is `Backpressure` and `can_retry` is set.
+    pub fn push(&self, item: T, can_retry: bool) -> Result<(), T> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.items.len() >= self.capacity {
+            match self.policy() {
+                OverflowPolicy::Backpressure if can_retry => {
+                    inner.stalled = true;
+                    return Err(item);
+                }
+                OverflowPolicy::DropOldest => {
+                    inner.items.pop_front();
+                    self.drops.inc();
+                }
+                _ => {
+                    self.drops.inc();
+                    return Ok(());
+                }
+            }
+        }
+        inner.items.push_back(item);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Takes the oldest packet. The flag tells whether a producer was
+    /// refused since the last time room was made.
+    pub fn poll_pop(&self, cx: &mut Context<'_>) -> Poll<(T, bool)> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.items.pop_front() {
+            Some(item) => {
+                let stalled = std::mem::take(&mut inner.stalled);
+                Poll::Ready((item, stalled))
+            }
+            None => {
+                inner.waker.replace(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    fn drain(queue: &PacketQueue<u8>) -> Vec<(u8, bool)> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut items = Vec::new();
+        while let Poll::Ready(item) = queue.poll_pop(&mut cx) {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        let queue = PacketQueue::new(2, DropCounter::default());
+        assert_eq!(queue.policy(), OverflowPolicy::DropNewest);
+        for i in 0..3 {
+            assert_eq!(queue.push(i, true), Ok(()));
+        }
+        assert_eq!(drain(&queue), [(0, false), (1, false)]);
+        assert_eq!(queue.drops().get(), 1);
+
+        queue.set_policy(OverflowPolicy::DropOldest);
+        for i in 0..3 {
+            assert_eq!(queue.push(i, true), Ok(()));
+        }
+        assert_eq!(drain(&queue), [(1, false), (2, false)]);
+        assert_eq!(queue.drops().get(), 2);
+
+        queue.set_policy(OverflowPolicy::Backpressure);
+        for i in 0..2 {
+            assert_eq!(queue.push(i, true), Ok(()));
+        }
+        assert_eq!(queue.push(2, true), Err(2));
+        // Without a way to retry, as with DropNewest.
+        assert_eq!(queue.push(3, false), Ok(()));
+        assert_eq!(drain(&queue), [(0, true), (1, false)]);
+        assert_eq!(queue.drops().get(), 3);
+    }
+}
@@ -0,0 +1,122 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::*;
+
+use super::connections::TcpState;
+use super::lwip::*;
+use super::tcp_stream::tcp_idle_timeout;
+use super::util;
+
+/// A TCP connection aborted to make room for a new one, see
+/// `NetStackBuilder::pcb_eviction`.
+#[derive(Debug, Clone)]
+pub struct Eviction {
+    /// Same as `TcpStream::local_addr`, the source of the flow.
+    pub local_addr: SocketAddr,
+    /// Same as `TcpStream::remote_addr`, the destination of the flow.
+    pub remote_addr: SocketAddr,
+    /// State of the connection when it was evicted.
+    pub state: TcpState,
+    /// Time since the peer was last heard from.
+    pub idle: Duration,
+}
+
+/// Aborts the connections of a netif that received nothing for `idle_ticks`,
+/// on the driver thread. Returns how many were aborted.
+///
+/// Streams of aborted pcbs fail with `Error::IdleTimeout` instead of
+/// `ConnectionAborted`.
+pub(crate) unsafe fn abort_idle_pcbs(netif_idx: u8, idle_ticks: u32) -> usize {
+    let mut aborted = 0;
+    let mut pcb = tcp_active_pcbs;
+    while !pcb.is_null() {
+        let pcb_v = std::ptr::read_unaligned(pcb);
+        if pcb_v.netif_idx == netif_idx && tcp_ticks.wrapping_sub(pcb_v.tmr) >= idle_ticks {
+            trace!(
+                "netstack tcp idle timeout {}",
+                util::to_socket_addr(&pcb_v.remote_ip, pcb_v.remote_port)
+            );
+            tcp_err(pcb, None);
+            tcp_abort(pcb);
+            // Only pcbs held by a stream have an error callback.
+            if pcb_v.errf.is_some() {
+                tcp_idle_timeout(pcb_v.callback_arg);
+            }
+            aborted += 1;
+        }
+        pcb = pcb_v.next;
+    }
+    aborted
+}
+
+/// Aborts every connection of a netif that is being removed, on the driver
+/// thread. Streams still holding one fail with `ERR_ABRT`, and pcbs left
+/// closing in the background by dropped streams are freed rather than
+/// waiting forever on a netif that no longer sends anything.
+pub(crate) unsafe fn abort_netif_pcbs(netif_idx: u8) {
+    for list in [tcp_active_pcbs, tcp_tw_pcbs] {
+        let mut pcb = list;
+        while !pcb.is_null() {
+            let pcb_v = std::ptr::read_unaligned(pcb);
+            if pcb_v.netif_idx == netif_idx {
+                tcp_abort(pcb);
+            }
+            pcb = pcb_v.next;
+        }
+    }
+}
+
+/// Frees a pcb of the netif, on the driver thread. The oldest pcb in
+/// TIME_WAIT goes first, then the connection that has been idle the
+/// longest. Connections still in their handshake are left alone.
+pub(crate) unsafe fn evict_pcb(netif_idx: u8) -> Option<Eviction> {
+    #[allow(non_upper_case_globals)]
+    let established = |state| {
+        matches!(
+            state,
+            tcp_state_ESTABLISHED
+                | tcp_state_FIN_WAIT_1
+                | tcp_state_FIN_WAIT_2
+                | tcp_state_CLOSE_WAIT
+                | tcp_state_CLOSING
+                | tcp_state_LAST_ACK
+        )
+    };
+    let pcb = most_idle_pcb(tcp_tw_pcbs, netif_idx, |_| true)
+        .or_else(|| most_idle_pcb(tcp_active_pcbs, netif_idx, established))?;
+    let pcb_v = std::ptr::read_unaligned(pcb);
+    let eviction = Eviction {
+        // Swapped, as in `TcpStream::new`.
+        local_addr: util::to_socket_addr(&pcb_v.remote_ip, pcb_v.remote_port),
+        remote_addr: util::to_socket_addr(&pcb_v.local_ip, pcb_v.local_port),
+        state: pcb_v.state.into(),
+        idle: Duration::from_millis(
+            tcp_ticks.wrapping_sub(pcb_v.tmr) as u64 * TCP_SLOW_INTERVAL as u64,
+        ),
+    };
+    trace!("netstack tcp evict {:?}", eviction);
+    tcp_abort(pcb);
+    Some(eviction)
+}
+
+unsafe fn most_idle_pcb(
+    list: *mut tcp_pcb,
+    netif_idx: u8,
+    filter: impl Fn(tcp_state) -> bool,
+) -> Option<*mut tcp_pcb> {
+    let mut found = None;
+    let mut max_idle = 0;
+    let mut pcb = list;
+    while !pcb.is_null() {
+        let pcb_v = std::ptr::read_unaligned(pcb);
+        let idle = tcp_ticks.wrapping_sub(pcb_v.tmr);
+        // Lists start with the newest pcb, ties go to the oldest.
+        if pcb_v.netif_idx == netif_idx && filter(pcb_v.state) && idle >= max_idle {
+            found = Some(pcb);
+            max_idle = idle;
+        }
+        pcb = pcb_v.next;
+    }
+    found
+}
@@ -0,0 +1,160 @@
+//! A synchronous interface to a `NetStack`, for hosts running their own
+//! event loop and for deterministic tests.
+//!
+//! Nothing blocks on the network: packets go in with `input` and come out of
+//! `poll_output`, and handles fail with `WouldBlock` instead of waiting. Every
+//! call returns once lwIP is done with it, so the packets a call makes lwIP
+//! emit can be taken right after it.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+use std::{cmp::min, io};
+
+use bytes::Bytes;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::Stream;
+use futures::task::{noop_waker_ref, Context, Poll};
+
+use super::builder::NetStackBuilder;
+use super::clock;
+use super::driver;
+use super::lwip::TCP_TMR_INTERVAL;
+use super::stack::NetStack;
+use super::tcp_listener::TcpListener;
+use super::tcp_stream::TcpStream;
+use super::udp;
+use crate::Error;
+
+/// Polls without registering for a wakeup, as nobody waits.
+fn poll_now<T>(f: impl FnOnce(&mut Context) -> Poll<T>) -> Option<T> {
+    match f(&mut Context::from_waker(noop_waker_ref())) {
+        Poll::Ready(v) => Some(v),
+        Poll::Pending => None,
+    }
+}
+
+/// A `NetStack` driven synchronously, see `NetStackBuilder::build_sync`.
+pub struct SyncNetStack {
+    stack: Pin<Box<NetStack>>,
+    listener: Pin<Box<TcpListener>>,
+    udp_send: udp::SendHalf,
+    udp_recv: udp::RecvHalf,
+}
+
+impl SyncNetStack {
+    pub(crate) fn new(builder: NetStackBuilder) -> Result<Self, Error> {
+        let (stack, listener, udp_socket) = NetStack::from_builder(builder)?;
+        let (udp_send, udp_recv) = udp_socket.split();
+        Ok(SyncNetStack {
+            stack,
+            listener,
+            udp_send,
+            udp_recv,
+        })
+    }
+
+    /// The underlying stack, for its counters and options.
+    pub fn stack(&self) -> &NetStack {
+        &self.stack
+    }
+
+    /// Feeds a packet from the TUN side to lwIP.
+    pub fn input(&mut self, pkt: &[u8]) -> Result<(), Error> {
+        self.stack.input_now(Bytes::copy_from_slice(pkt))
+    }
+
+    /// Takes the next packet emitted toward the TUN side, if any.
+    pub fn poll_output(&mut self) -> Option<Bytes> {
+        // Let the commands of dropped or written handles run first.
+        driver::call(|| {});
+        poll_now(|cx| self.stack.as_mut().poll_next(cx))
+            .flatten()
+            .and_then(Result::ok)
+    }
+
+    /// Tells the stack that `elapsed` has passed since the last call, and
+    /// runs the timers that are due. Returns how long until the next one is,
+    /// at most 250ms.
+    ///
+    /// With a `VirtualClock` set, this moves it forward, stopping at every
+    /// timer due in between. With any other clock the host calls this as
+    /// time actually passes. lwIP's timers are shared by all stacks.
+    pub fn advance_time(&mut self, elapsed: Duration) -> Duration {
+        min(
+            clock::advance(elapsed),
+            Duration::from_millis(TCP_TMR_INTERVAL as u64),
+        )
+    }
+
+    /// Takes the next accepted TCP connection, if any.
+    pub fn accept(&mut self) -> Option<TcpHandle> {
+        poll_now(|cx| self.listener.as_mut().poll_next(cx))
+            .flatten()
+            .map(|(stream, _, _)| TcpHandle { stream })
+    }
+
+    /// Takes the next UDP datagram as `(data, src_addr, dst_addr)`, if any.
+    pub fn recv_udp(&mut self) -> Option<(Vec<u8>, SocketAddr, SocketAddr)> {
+        poll_now(|cx| Pin::new(&mut self.udp_recv).poll_next(cx)).flatten()
+    }
+
+    /// Sends a datagram from `src_addr` to `dst_addr` on the TUN side.
+    pub fn send_udp(
+        &self,
+        data: &[u8],
+        src_addr: &SocketAddr,
+        dst_addr: &SocketAddr,
+    ) -> io::Result<()> {
+        self.udp_send.send_to(data, src_addr, dst_addr)
+    }
+}
+
+/// A TCP connection of a `SyncNetStack`, with non-blocking reads and
+/// writes.
+///
+/// Dropping it closes the connection like dropping a `TcpStream`.
+pub struct TcpHandle {
+    stream: Pin<Box<TcpStream>>,
+}
+
+impl TcpHandle {
+    pub fn local_addr(&self) -> &SocketAddr {
+        self.stream.local_addr()
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        self.stream.remote_addr()
+    }
+
+    /// Reads received data, failing with `WouldBlock` if there is none yet.
+    /// Returns 0 once the peer has closed its side.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_now(|cx| self.stream.as_mut().poll_read(cx, buf)).unwrap_or_else(would_block)
+    }
+
+    /// Queues data for sending, failing with `WouldBlock` while lwIP's send
+    /// buffer is full.
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        poll_now(|cx| self.stream.as_mut().poll_write(cx, buf)).unwrap_or_else(would_block)
+    }
+
+    /// Shuts the connection down for writing. The FIN follows the data
+    /// written so far.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        poll_now(|cx| self.stream.as_mut().poll_close(cx)).unwrap_or(Ok(()))
+    }
+
+    /// The underlying stream, for its options.
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn into_stream(self) -> Pin<Box<TcpStream>> {
+        self.stream
+    }
+}
+
+fn would_block<T>() -> io::Result<T> {
+    Err(io::ErrorKind::WouldBlock.into())
+}
@@ -1,181 +1,484 @@
 use std::marker::PhantomPinned;
-use std::{io, os::raw, pin::Pin, sync::Once, time};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(not(feature = "tokio"))]
+use std::time::Duration;
+use std::{future::Future, io, net::SocketAddr, os::raw, pin::Pin, ptr::null};
 
+use bytes::Bytes;
 use futures::sink::Sink;
 use futures::stream::Stream;
-use futures::task::{Context, Poll, Waker};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use futures::task::{AtomicWaker, Context, Poll};
 
+use super::builder::{NetStackBuilder, TcpConfig};
+use super::capture::{Capture, Direction};
+use super::clock::{self, Clock};
+use super::connections::ConnectionInfo;
+use super::driver;
+use super::icmp::{self, IcmpMode, IcmpSocket};
 use super::lwip::*;
-use super::output::{output_ip4, output_ip6, OUTPUT_CB_PTR};
+use super::output::init_netif;
+use super::pbuf::pbuf_from_bytes;
+use super::queue::{DropCounter, OverflowPolicy, PacketQueue};
+use super::reaper::{abort_idle_pcbs, abort_netif_pcbs};
+use super::stats::{Counters, Stats, StatsHandle};
 use super::tcp_listener::TcpListener;
+use super::tcp_stream::TcpStream;
 use super::udp::UdpSocket;
-use super::LWIP_MUTEX;
+use super::util;
+use super::vnet::{self, VNET_HDR_LEN};
 use crate::Error;
 
-static LWIP_INIT: Once = Once::new();
+/// Packets written into the sink that the driver may not have fed to lwIP
+/// yet, before the sink applies backpressure.
+const MAX_PENDING_INPUT: usize = 256;
 
+/// Flags of a netif generating every checksum, the others check them.
+const CHECKSUM_GEN: u32 = NETIF_CHECKSUM_GEN_IP
+    | NETIF_CHECKSUM_GEN_UDP
+    | NETIF_CHECKSUM_GEN_TCP
+    | NETIF_CHECKSUM_GEN_ICMP
+    | NETIF_CHECKSUM_GEN_ICMP6;
+
+#[derive(Default)]
+struct PendingInput {
+    count: AtomicUsize,
+    waker: AtomicWaker,
+    /// First packet refused since the sink last reported an error.
+    error: Mutex<Option<Error>>,
+}
+
+impl PendingInput {
+    fn set_error(&self, err: Error) {
+        self.error.lock().unwrap().get_or_insert(err);
+    }
+
+    fn take_error(&self) -> io::Result<()> {
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A netstack instance backed by its own lwIP netif.
+///
+/// The lwIP core (PCB tables, memory pools, timers) is process-wide and
+/// runs on a single driver thread, but every `NetStack` adds a dedicated
+/// netif. The listener and UDP socket created along with it are bound to
+/// that netif, so packets and connections of one instance never reach
+/// another.
 pub struct NetStack {
-    waker: Option<Waker>,
-    tx: Sender<Vec<u8>>,
-    rx: Receiver<Vec<u8>>,
-    sink_buf: Option<Vec<u8>>, // We're flushing per item, no need large buffer.
+    netif: usize,
+    output_queue: PacketQueue<Bytes>,
+    counters: Arc<Counters>,
+    tcp: TcpConfig,
+    pending_input: Arc<PendingInput>,
+    ticker: Option<u64>,
+    icmp_mode: AtomicU8,
+    icmp_pcbs: [usize; 2],
+    capture: RwLock<Option<Capture>>,
+    vnet_hdr: bool,
     _pin: PhantomPinned,
 }
 
 impl NetStack {
+    /// Creates a stack with the default options, see `NetStackBuilder`.
+    #[allow(clippy::type_complexity)]
     pub fn new() -> Result<(Pin<Box<Self>>, Pin<Box<TcpListener>>, Pin<Box<UdpSocket>>), Error> {
-        Ok((
-            NetStack::_new(512),
-            TcpListener::new()?,
-            UdpSocket::new(64)?,
-        ))
+        NetStackBuilder::default().build()
     }
 
+    pub fn builder() -> NetStackBuilder {
+        NetStackBuilder::default()
+    }
+
+    /// Creates a stack with the given output and UDP queue sizes.
+    #[deprecated(note = "use NetStack::builder() with buffer_size and udp_buffer_size")]
+    #[allow(clippy::type_complexity)]
     pub fn with_buffer_size(
         stack_buffer_size: usize,
         udp_buffer_size: usize,
     ) -> Result<(Pin<Box<Self>>, Pin<Box<TcpListener>>, Pin<Box<UdpSocket>>), Error> {
-        Ok((
-            NetStack::_new(stack_buffer_size),
-            TcpListener::new()?,
-            UdpSocket::new(udp_buffer_size)?,
-        ))
+        NetStackBuilder::default()
+            .buffer_size(stack_buffer_size)
+            .udp_buffer_size(udp_buffer_size)
+            .build()
     }
 
-    fn _new(buffer_size: usize) -> Pin<Box<Self>> {
-        LWIP_INIT.call_once(|| unsafe { lwip_init() });
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn from_builder(
+        builder: NetStackBuilder,
+    ) -> Result<(Pin<Box<Self>>, Pin<Box<TcpListener>>, Pin<Box<UdpSocket>>), Error> {
+        let stack = NetStack::_new(&builder)?;
+        let tcp_listener = TcpListener::new(stack.netif, builder.backlog, builder.tcp)?;
+        let udp_socket = UdpSocket::new(
+            stack.netif,
+            builder.udp_buffer_size,
+            stack.counters.udp_drops.clone(),
+        )?;
+        Ok((stack, tcp_listener, udp_socket))
+    }
 
-        unsafe {
-            (*netif_list).output = Some(output_ip4);
-            (*netif_list).output_ip6 = Some(output_ip6);
-            (*netif_list).mtu = 1500;
-        }
+    fn _new(builder: &NetStackBuilder) -> Result<Pin<Box<Self>>, Error> {
+        let counters = Arc::new(Counters::default());
+        let mut stack = Box::pin(NetStack {
+            netif: 0,
+            output_queue: PacketQueue::new(builder.buffer_size, counters.output_drops.clone()),
+            counters,
+            tcp: builder.tcp,
+            pending_input: Arc::default(),
+            ticker: None,
+            icmp_mode: AtomicU8::new(IcmpMode::default() as u8),
+            icmp_pcbs: [0, 0],
+            capture: RwLock::new(None),
+            vnet_hdr: builder.virtio_net_hdr,
+            _pin: PhantomPinned,
+        });
 
-        let (tx, rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel(buffer_size);
+        let state = &*stack as *const NetStack as usize;
+        let mode = &stack.icmp_mode as *const AtomicU8 as usize;
+        let counters = stack.counters.clone();
+        let (netif, icmp_pcbs, ticker) = driver::call(|| unsafe {
+            let netif = Box::into_raw(Box::new(std::mem::zeroed::<netif>()));
+            if netif_add(
+                netif,
+                null(),
+                null(),
+                null(),
+                state as *mut raw::c_void,
+                Some(init_netif),
+                Some(ip_input),
+            )
+            .is_null()
+            {
+                drop(Box::from_raw(netif));
+                return Err(Error::from_err(err_enum_t_ERR_IF as err_t));
+            }
+            (*netif).mtu = builder.mtu;
+            (*netif).mtu6 = builder.mtu;
+            let mut chksum_flags = if builder.checksum_validation {
+                NETIF_CHECKSUM_ENABLE_ALL
+            } else {
+                CHECKSUM_GEN
+            };
+            if builder.virtio_net_hdr {
+                // Left to the kernel, see `vnet::copy_from_pbuf`.
+                chksum_flags &= !NETIF_CHECKSUM_GEN_TCP;
+            }
+            (*netif).chksum_flags = chksum_flags as u16_t;
+            netif_set_up(netif);
+            netif_set_link_up(netif);
+            let icmp_pcbs = match icmp::new_raw_pcbs(
+                netif as usize,
+                Some(icmp::icmp_mode_recv_cb),
+                mode as *mut raw::c_void,
+            ) {
+                Ok(pcbs) => pcbs,
+                Err(e) => {
+                    netif_remove(netif);
+                    drop(Box::from_raw(netif));
+                    return Err(e);
+                }
+            };
+            let netif_idx = (*netif).num + 1;
+            let ticker = builder.idle_timeout.map(|timeout| {
+                let idle_ticks = util::slow_ticks(timeout);
+                driver::add_ticker(builder.timer_period, move || {
+                    let aborted = abort_idle_pcbs(netif_idx, idle_ticks);
+                    counters
+                        .idle_timeouts
+                        .fetch_add(aborted as u64, Ordering::Relaxed);
+                })
+            });
+            Ok((netif as usize, icmp_pcbs, ticker))
+        })?;
+        let me = unsafe { stack.as_mut().get_unchecked_mut() };
+        me.netif = netif;
+        me.icmp_pcbs = icmp_pcbs;
+        me.ticker = ticker;
 
-        let stack = Box::pin(NetStack {
-            waker: None,
-            tx,
-            rx,
-            sink_buf: None,
-            _pin: PhantomPinned::default(),
-        });
+        Ok(stack)
+    }
+
+    /// Opens a TCP connection toward a host on the TUN side of this stack.
+    ///
+    /// The SYN is sent from `src` to `dst`. Following the convention of
+    /// accepted streams, `local_addr()` of the returned stream is the TUN
+    /// side endpoint (`dst`) and `remote_addr()` is `src`.
+    ///
+    /// Fails with `ConnectionRefused` if the peer answers with a RST, and
+    /// with `TimedOut` if lwIP gives up retransmitting the SYN.
+    pub fn connect(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> impl Future<Output = io::Result<Pin<Box<TcpStream>>>> + Send + 'static {
+        TcpStream::connect(self.netif, self.tcp, src, dst)
+    }
+
+    /// Sets how echo requests are handled while no `IcmpSocket` of this
+    /// stack is alive.
+    pub fn set_icmp_mode(&self, mode: IcmpMode) {
+        self.icmp_mode.store(mode as u8, Ordering::Relaxed);
+    }
 
-        unsafe {
-            OUTPUT_CB_PTR = &*stack as *const NetStack as usize;
+    /// Creates a socket taking the echo requests sent into this stack.
+    pub fn icmp_socket(&self, buffer_size: usize) -> Result<Pin<Box<IcmpSocket>>, Error> {
+        IcmpSocket::new(self.netif, buffer_size)
+    }
+
+    /// Sets what happens to packets emitted by lwIP while the output queue
+    /// is full, i.e. while the stream half is not read fast enough.
+    pub fn set_output_policy(&self, policy: OverflowPolicy) {
+        self.output_queue.set_policy(policy);
+    }
+
+    /// Counts the packets dropped from the output queue.
+    pub fn output_drops(&self) -> DropCounter {
+        self.output_queue.drops().clone()
+    }
+
+    /// Returns the counters of this stack.
+    pub fn stats(&self) -> Stats {
+        self.stats_handle().stats()
+    }
+
+    /// Returns a snapshot of the TCP connections of this stack, including
+    /// those in TIME_WAIT.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.stats_handle().connections()
+    }
+
+    /// Returns a handle reading the counters of this stack, which remains
+    /// usable after the stack has been split.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            netif_idx: unsafe { (*(self.netif as *const netif)).num } + 1,
+            counters: self.counters.clone(),
         }
+    }
+
+    /// Captures the packets written into the sink of this stack and those it
+    /// emits toward its stream, including the ones dropped when the output
+    /// queue overflows. Stops capturing with `None`.
+    pub fn set_capture(&self, capture: Option<Capture>) {
+        *self.capture.write().unwrap() = capture;
+    }
 
-        tokio::spawn(async move {
-            loop {
-                {
-                    let _g = LWIP_MUTEX.lock();
-                    unsafe { sys_check_timeouts() };
+    fn capture(&self, direction: Direction, pkt: &Bytes) {
+        if let Some(capture) = &*self.capture.read().unwrap() {
+            match direction {
+                // Captures hold IP packets, inbound ones are stripped first.
+                Direction::Outbound if self.vnet_hdr => {
+                    capture.record(direction, &pkt.slice(VNET_HDR_LEN..))
                 }
-                tokio::time::sleep(time::Duration::from_millis(250)).await;
+                _ => capture.record(direction, pkt),
             }
-        });
-
-        stack
+        }
     }
 
-    pub fn output(&mut self, pkt: Vec<u8>) {
-        if let Err(_) = self.tx.try_send(pkt) {
-            // log::trace!("try send stack output pkt failed: {}", e);
+    /// Strips the `virtio_net_hdr` of a packet written into the stack, if
+    /// it has one. Returns whether its checksums are to be trusted, see
+    /// `vnet::strip`.
+    fn strip(&self, pkt: &mut Bytes) -> Result<bool, Error> {
+        if !self.vnet_hdr {
+            return Ok(false);
         }
-        if let Some(waker) = self.waker.as_ref() {
-            waker.wake_by_ref();
+        vnet::strip(pkt).ok_or_else(|| {
+            self.counters.input_errors.fetch_add(1, Ordering::Relaxed);
+            Error::from_err(err_enum_t_ERR_VAL as err_t)
+        })
+    }
+
+    /// Sets the clock read by lwIP's timers and the periodic work of every
+    /// stack, e.g. a `VirtualClock` in tests. Defaults to the system's
+    /// monotonic clock.
+    ///
+    /// Best set before any stack is created: time going backwards delays
+    /// the pending timeouts.
+    pub fn set_clock(clock: impl Clock + 'static) {
+        clock::set(clock);
+    }
+
+    /// Runs the timers of every stack that are due: lwIP's retransmissions,
+    /// delayed ACKs and TIME_WAIT, the idle timeouts and the expiry of UDP
+    /// flows. They run on the driver thread either way, the `tokio` feature
+    /// only decides whether that thread runs them on its own. Without it
+    /// nothing else does.
+    ///
+    /// Time is read from the clock set with `set_clock`. Returns how long
+    /// until the call is due again at the latest, never more than 250ms as
+    /// lwIP starts its TCP timer along with the first connection.
+    #[cfg(not(feature = "tokio"))]
+    pub fn poll_timers() -> Duration {
+        let wait = driver::call(driver::run_timers);
+        wait.min(Duration::from_millis(TCP_TMR_INTERVAL as u64))
+    }
+
+    /// Feeds a packet to lwIP and waits until it has been processed, past
+    /// the packets written into the sink before.
+    pub(crate) fn input_now(&self, mut pkt: Bytes) -> Result<(), Error> {
+        if pkt.is_empty() {
+            return Ok(());
         }
+        let trusted = self.strip(&mut pkt);
+        self.counters.add_in(pkt.len());
+        let trusted = trusted?;
+        self.capture(Direction::Inbound, &pkt);
+        let (netif, counters) = (self.netif, &self.counters);
+        driver::call(|| unsafe { input(netif as *mut netif, pkt, trusted, counters) })
+    }
+
+    /// Whether packets carry a `virtio_net_hdr`.
+    pub(crate) fn vnet_hdr(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    /// Queues a packet emitted by lwIP, handing it back if lwIP should retry.
+    /// Packets are captured here, those dropped as the queue overflows too,
+    /// but not those handed back, which lwIP emits again.
+    pub(crate) fn output(&self, pkt: Bytes) -> Result<(), Bytes> {
+        self.output_queue.push(pkt.clone(), true)?;
+        self.capture(Direction::Outbound, &pkt);
+        Ok(())
     }
 }
 
 impl Drop for NetStack {
     fn drop(&mut self) {
         log::trace!("drop netstack");
-        unsafe {
-            let _g = LWIP_MUTEX.lock();
-            OUTPUT_CB_PTR = 0x0;
-        };
+        let (netif, icmp_pcbs, ticker) = (self.netif, self.icmp_pcbs, self.ticker);
+        driver::call(|| unsafe {
+            if let Some(ticker) = ticker {
+                driver::remove_ticker(ticker);
+            }
+            icmp::remove_raw_pcbs(icmp_pcbs);
+            let netif = netif as *mut netif;
+            abort_netif_pcbs((*netif).num + 1);
+            netif_remove(netif);
+            drop(Box::from_raw(netif));
+        });
     }
 }
 
 impl Stream for NetStack {
-    type Item = io::Result<Vec<u8>>;
+    type Item = io::Result<Bytes>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let me = unsafe { self.get_unchecked_mut() };
-        match me.rx.poll_recv(cx) {
-            Poll::Ready(Some(pkt)) => Poll::Ready(Some(Ok(pkt))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => {
-                me.waker.replace(cx.waker().clone());
-                Poll::Pending
+        match self.output_queue.poll_pop(cx) {
+            Poll::Ready((pkt, stalled)) => {
+                let hdr_len = if self.vnet_hdr { VNET_HDR_LEN } else { 0 };
+                self.counters.add_out(pkt.len() - hdr_len);
+                if stalled {
+                    // There is room again for the segments refused under
+                    // backpressure, no need to wait for the TCP timer.
+                    driver::submit(|| unsafe { tcp_txnow() });
+                }
+                Poll::Ready(Some(Ok(pkt)))
             }
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-impl Sink<Vec<u8>> for NetStack {
+/// Packets written into the sink are fed to lwIP in order on the driver
+/// thread, flushing does not wait for it. Packets refused are counted in
+/// `Stats::input_errors`, and the first one since the last error fails the
+/// next `poll_ready` or `poll_flush`. The sink can be used again after.
+impl Sink<Bytes> for NetStack {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let me = unsafe { self.get_unchecked_mut() };
-        if me.sink_buf.is_none() {
+        let pending = &self.pending_input;
+        pending.take_error()?;
+        if pending.count.load(Ordering::Acquire) < MAX_PENDING_INPUT {
+            return Poll::Ready(Ok(()));
+        }
+        pending.waker.register(cx.waker());
+        if pending.count.load(Ordering::Acquire) < MAX_PENDING_INPUT {
             Poll::Ready(Ok(()))
         } else {
-            unsafe { Pin::new_unchecked(me) }.poll_flush(cx)
+            Poll::Pending
         }
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        unsafe { self.get_unchecked_mut() }.sink_buf.replace(item);
+    fn start_send(self: Pin<&mut Self>, mut item: Bytes) -> Result<(), Self::Error> {
+        if item.is_empty() {
+            return Ok(());
+        }
+        let trusted = self.strip(&mut item);
+        // Counted without the `virtio_net_hdr`, as are the packets emitted.
+        self.counters.add_in(item.len());
+        let trusted = match trusted {
+            Ok(trusted) => trusted,
+            Err(err) => {
+                self.pending_input.set_error(err);
+                return Ok(());
+            }
+        };
+        self.capture(Direction::Inbound, &item);
+        self.pending_input.count.fetch_add(1, Ordering::AcqRel);
+        let netif = self.netif;
+        let counters = self.counters.clone();
+        let pending = self.pending_input.clone();
+        driver::submit(move || {
+            if let Err(err) = unsafe { input(netif as *mut netif, item, trusted, &counters) } {
+                pending.set_error(err);
+            }
+            if pending.count.fetch_sub(1, Ordering::AcqRel) >= MAX_PENDING_INPUT {
+                pending.waker.wake();
+            }
+        });
         Ok(())
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if let Some(item) = unsafe { self.get_unchecked_mut() }.sink_buf.take() {
-            if item.is_empty() {
-                return Poll::Ready(Ok(()));
-            }
-            unsafe {
-                let _g = LWIP_MUTEX.lock();
-
-                let pbuf = pbuf_alloc(pbuf_layer_PBUF_RAW, item.len() as u16_t, pbuf_type_PBUF_RAM);
-                if pbuf.is_null() {
-                    log::trace!("pbuf_alloc null alloc");
-                    return Poll::Pending;
-                }
-                pbuf_take(
-                    pbuf,
-                    item.as_ptr() as *const raw::c_void,
-                    item.len() as u16_t,
-                );
-
-                if let Some(input_fn) = (*netif_list).input {
-                    let err = input_fn(pbuf, netif_list);
-                    if err == err_enum_t_ERR_OK as err_t {
-                        Poll::Ready(Ok(()))
-                    } else {
-                        pbuf_free(pbuf);
-                        Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::Interrupted,
-                            format!("input error: {}", err),
-                        )))
-                    }
-                } else {
-                    pbuf_free(pbuf);
-                    Poll::Ready(Err(io::Error::new(
-                        io::ErrorKind::Interrupted,
-                        "input fn not set",
-                    )))
-                }
-            }
-        } else {
-            Poll::Ready(Ok(()))
-        }
+        Poll::Ready(self.pending_input.take_error())
     }
 
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 }
+
+/// Feeds a packet to lwIP, on the driver thread. The checksums of a
+/// `trusted` packet are not checked.
+unsafe fn input(
+    netif: *mut netif,
+    pkt: Bytes,
+    trusted: bool,
+    counters: &Counters,
+) -> Result<(), Error> {
+    let pbuf = pbuf_from_bytes(pkt);
+    if pbuf.is_null() {
+        log::trace!("pbuf_alloc null alloc");
+        counters.input_errors.fetch_add(1, Ordering::Relaxed);
+        return Err(Error::OutOfMemory);
+    }
+    // lwIP only counts bad checksums globally, all of them are found while
+    // the packet is being processed.
+    let chkerr = netstack_chkerr();
+    let chksum_flags = (*netif).chksum_flags;
+    if trusted {
+        (*netif).chksum_flags &= CHECKSUM_GEN as u16_t;
+    }
+    let err = match (*netif).input {
+        Some(input_fn) => input_fn(pbuf, netif),
+        None => err_enum_t_ERR_IF as err_t,
+    };
+    (*netif).chksum_flags = chksum_flags;
+    let bad_checksums = netstack_chkerr().wrapping_sub(chkerr);
+    if bad_checksums > 0 {
+        counters
+            .checksum_errors
+            .fetch_add(bad_checksums as u64, Ordering::Relaxed);
+    }
+    if err != err_enum_t_ERR_OK as err_t {
+        log::trace!("netstack input error {}", err);
+        pbuf_free(pbuf);
+        counters.input_errors.fetch_add(1, Ordering::Relaxed);
+        return Err(Error::from_err(err));
+    }
+    Ok(())
+}
@@ -0,0 +1,240 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::connections::{tcp_connections, ConnectionInfo, TcpState};
+use super::driver;
+use super::lwip::*;
+use super::queue::DropCounter;
+
+/// Snapshot of the counters of a `NetStack`, see `NetStack::stats`.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Packets written into the sink half of the stack.
+    pub packets_in: u64,
+    /// Bytes of the IP packets, without their `virtio_net_hdr` if any.
+    pub bytes_in: u64,
+    /// Packets read from the stream half of the stack.
+    pub packets_out: u64,
+    /// Bytes of the IP packets, without their `virtio_net_hdr` if any.
+    pub bytes_out: u64,
+    /// Packets lwIP refused to take in.
+    pub input_errors: u64,
+    /// Packets dropped for a bad checksum, see
+    /// `NetStackBuilder::checksum_validation`.
+    pub checksum_errors: u64,
+    /// Packets dropped because the output queue was full.
+    pub output_drops: u64,
+    /// Datagrams dropped because the `UdpSocket` of the stack, or one of its
+    /// flows, was not read fast enough.
+    pub udp_drops: u64,
+    /// TCP connections aborted after `NetStackBuilder::idle_timeout`.
+    pub idle_timeouts: u64,
+    /// TCP pcbs of this stack, by state.
+    pub tcp_pcbs: TcpPcbStats,
+    /// lwIP's own counters. The lwIP core is shared by all stacks, so are
+    /// these.
+    pub lwip: LwipStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpPcbStats {
+    pub closed: usize,
+    pub listen: usize,
+    pub syn_sent: usize,
+    pub syn_rcvd: usize,
+    pub established: usize,
+    pub fin_wait_1: usize,
+    pub fin_wait_2: usize,
+    pub close_wait: usize,
+    pub closing: usize,
+    pub last_ack: usize,
+    pub time_wait: usize,
+}
+
+impl TcpPcbStats {
+    fn add(&mut self, state: TcpState) {
+        let counter = match state {
+            TcpState::Closed => &mut self.closed,
+            TcpState::Listen => &mut self.listen,
+            TcpState::SynSent => &mut self.syn_sent,
+            TcpState::SynRcvd => &mut self.syn_rcvd,
+            TcpState::Established => &mut self.established,
+            TcpState::FinWait1 => &mut self.fin_wait_1,
+            TcpState::FinWait2 => &mut self.fin_wait_2,
+            TcpState::CloseWait => &mut self.close_wait,
+            TcpState::Closing => &mut self.closing,
+            TcpState::LastAck => &mut self.last_ack,
+            TcpState::TimeWait => &mut self.time_wait,
+        };
+        *counter += 1;
+    }
+}
+
+/// Counters of one protocol, as kept by lwIP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtoStats {
+    pub xmit: u64,
+    pub recv: u64,
+    pub drop: u64,
+    /// Checksum, length, memory, routing, protocol and option errors.
+    pub err: u64,
+}
+
+impl From<netstack_proto_stats> for ProtoStats {
+    fn from(s: netstack_proto_stats) -> Self {
+        ProtoStats {
+            xmit: s.xmit as u64,
+            recv: s.recv as u64,
+            drop: s.drop as u64,
+            err: s.err as u64,
+        }
+    }
+}
+
+/// Usage of the lwIP heap or of a memory pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub avail: u64,
+    pub used: u64,
+    pub max: u64,
+    /// Failed allocations.
+    pub err: u64,
+}
+
+impl From<netstack_mem_stats> for PoolStats {
+    fn from(s: netstack_mem_stats) -> Self {
+        PoolStats {
+            avail: s.avail as u64,
+            used: s.used as u64,
+            max: s.max as u64,
+            err: s.err as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LwipStats {
+    pub ip4: ProtoStats,
+    pub ip6: ProtoStats,
+    pub icmp: ProtoStats,
+    pub icmp6: ProtoStats,
+    pub tcp: ProtoStats,
+    pub udp: ProtoStats,
+    pub heap: PoolStats,
+    pub pbuf_pool: PoolStats,
+    pub pbuf: PoolStats,
+    pub raw_pcb: PoolStats,
+    pub udp_pcb: PoolStats,
+    pub tcp_pcb: PoolStats,
+    pub tcp_pcb_listen: PoolStats,
+    pub tcp_seg: PoolStats,
+}
+
+impl From<netstack_lwip_stats> for LwipStats {
+    fn from(s: netstack_lwip_stats) -> Self {
+        LwipStats {
+            ip4: s.ip4.into(),
+            ip6: s.ip6.into(),
+            icmp: s.icmp.into(),
+            icmp6: s.icmp6.into(),
+            tcp: s.tcp.into(),
+            udp: s.udp.into(),
+            heap: s.heap.into(),
+            pbuf_pool: s.pbuf_pool.into(),
+            pbuf: s.pbuf.into(),
+            raw_pcb: s.raw_pcb.into(),
+            udp_pcb: s.udp_pcb.into(),
+            tcp_pcb: s.tcp_pcb.into(),
+            tcp_pcb_listen: s.tcp_pcb_listen.into(),
+            tcp_seg: s.tcp_seg.into(),
+        }
+    }
+}
+
+/// Counters kept on the Rust side of a `NetStack`.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub packets_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub packets_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub input_errors: AtomicU64,
+    pub checksum_errors: AtomicU64,
+    pub output_drops: DropCounter,
+    pub udp_drops: DropCounter,
+    pub idle_timeouts: AtomicU64,
+}
+
+impl Counters {
+    pub fn add_in(&self, bytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, bytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Gives access to the counters of a `NetStack`, also once it has been
+/// split or moved into a task.
+#[derive(Clone)]
+pub struct StatsHandle {
+    pub(crate) netif_idx: u8,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl StatsHandle {
+    pub fn stats(&self) -> Stats {
+        let c = &self.counters;
+        let netif_idx = self.netif_idx;
+        let (tcp_pcbs, lwip) = driver::call(|| unsafe {
+            let mut lwip = std::mem::zeroed::<netstack_lwip_stats>();
+            netstack_get_lwip_stats(&mut lwip);
+            (count_tcp_pcbs(netif_idx), lwip)
+        });
+        Stats {
+            packets_in: c.packets_in.load(Ordering::Relaxed),
+            bytes_in: c.bytes_in.load(Ordering::Relaxed),
+            packets_out: c.packets_out.load(Ordering::Relaxed),
+            bytes_out: c.bytes_out.load(Ordering::Relaxed),
+            input_errors: c.input_errors.load(Ordering::Relaxed),
+            checksum_errors: c.checksum_errors.load(Ordering::Relaxed),
+            output_drops: c.output_drops.get(),
+            udp_drops: c.udp_drops.get(),
+            idle_timeouts: c.idle_timeouts.load(Ordering::Relaxed),
+            tcp_pcbs,
+            lwip: lwip.into(),
+        }
+    }
+
+    /// Same as `NetStack::connections`.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let netif_idx = self.netif_idx;
+        driver::call(|| unsafe { tcp_connections(netif_idx) })
+    }
+}
+
+/// Walks lwIP's TCP pcb lists, on the driver thread.
+unsafe fn count_tcp_pcbs(netif_idx: u8) -> TcpPcbStats {
+    let mut stats = TcpPcbStats::default();
+    let lists = [
+        tcp_bound_pcbs,
+        tcp_listen_pcbs.pcbs,
+        tcp_active_pcbs,
+        tcp_tw_pcbs,
+    ];
+    for list in lists {
+        let mut pcb = list;
+        while !pcb.is_null() {
+            // Listening pcbs are smaller, but start with the same fields.
+            let lpcb = std::ptr::read_unaligned(pcb as *const tcp_pcb_listen);
+            if lpcb.netif_idx == netif_idx {
+                stats.add(lpcb.state.into());
+            }
+            pcb = lpcb.next as *mut tcp_pcb;
+        }
+    }
+    stats
+}
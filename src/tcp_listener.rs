@@ -1,17 +1,386 @@
+use std::cell::Cell;
+use std::collections::HashSet;
 use std::marker::PhantomPinned;
 use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
 use std::{net::SocketAddr, os::raw, pin::Pin};
 
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::stream::Stream;
 use futures::task::{Context, Poll};
+use futures::StreamExt;
 use log::*;
-use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
 
+use super::builder::TcpConfig;
+use super::driver;
+use super::icmp::{self, Unreachable};
 use super::lwip::*;
+use super::reaper::{evict_pcb, Eviction};
 use super::tcp_stream::TcpStream;
-use super::LWIP_MUTEX;
+use super::util;
 use crate::Error;
 
+type FlowKey = (SocketAddr, SocketAddr);
+
+#[derive(Debug, Clone, Copy)]
+enum Verdict {
+    Accept,
+    Reset,
+    Unreachable(Unreachable),
+}
+
+thread_local! {
+    // The SYN whose filter is running on this thread, if any.
+    static FILTERING: Cell<Option<FlowKey>> = const { Cell::new(None) };
+    // Decision for the SYN currently going through the hook.
+    static VERDICT: Cell<Option<Verdict>> = const { Cell::new(None) };
+}
+
+/// A SYN waiting for the pre-accept filter of a `TcpListener` to decide its
+/// fate.
+///
+/// The request may be resolved from within the filter, or moved elsewhere
+/// and resolved later, e.g. once the upstream connection is established.
+/// While it is held, retransmissions of the SYN are silently dropped.
+/// Dropping an unresolved request drops the SYN, the filter runs again on
+/// the next retransmission.
+///
+/// A filter must not resolve requests other than the one it is given.
+pub struct SynRequest {
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    netif_idx: u8,
+    seqno: u32,
+    pkt: Vec<u8>,
+    held: Arc<Mutex<HashSet<FlowKey>>>,
+}
+
+impl SynRequest {
+    /// The TUN side endpoint which sent the SYN.
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+
+    /// The endpoint the SYN is addressed to.
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    /// Lets the handshake proceed, the resulting `TcpStream` is yielded by
+    /// the listener as usual.
+    pub fn accept(self) {
+        self.resolve(Verdict::Accept);
+    }
+
+    /// Answers the SYN with a RST, the peer sees "connection refused".
+    pub fn reset(self) {
+        self.resolve(Verdict::Reset);
+    }
+
+    /// Answers the SYN with an ICMP/ICMPv6 destination unreachable message.
+    pub fn unreachable(self, reason: Unreachable) {
+        self.resolve(Verdict::Unreachable(reason));
+    }
+
+    fn key(&self) -> FlowKey {
+        (self.local_addr, self.remote_addr)
+    }
+
+    fn tcp_hdr(&self) -> [u8; 8] {
+        let mut hdr = [0u8; 8];
+        hdr[0..2].copy_from_slice(&self.local_addr.port().to_be_bytes());
+        hdr[2..4].copy_from_slice(&self.remote_addr.port().to_be_bytes());
+        hdr[4..8].copy_from_slice(&self.seqno.to_be_bytes());
+        hdr
+    }
+
+    fn resolve(self, verdict: Verdict) {
+        if FILTERING.with(|f| f.get()) == Some(self.key()) {
+            // Still inside the hook, which applies the verdict itself.
+            VERDICT.with(|v| v.set(Some(verdict)));
+            return;
+        }
+        driver::submit(move || {
+            unsafe { self.apply(verdict) };
+            // Releases the flow only once the SYN went through, until then
+            // its retransmissions are still dropped.
+            drop(self);
+        });
+    }
+
+    /// Applies a verdict given outside of the filter, on the driver thread.
+    unsafe fn apply(&self, verdict: Verdict) {
+        let netif = netif_get_by_index(self.netif_idx);
+        if netif.is_null() {
+            return;
+        }
+        if let Verdict::Unreachable(reason) = verdict {
+            if let Err(e) = icmp::output_unreachable(
+                netif,
+                reason,
+                IP_PROTO_TCP as u8,
+                &self.local_addr,
+                &self.remote_addr,
+                self.tcp_hdr(),
+            ) {
+                warn!("netstack tcp unreachable failed: {}", e);
+            }
+            return;
+        }
+        // Feed the SYN back to lwIP, the hook picks up the verdict.
+        let pbuf = pbuf_alloc(
+            pbuf_layer_PBUF_RAW,
+            self.pkt.len() as u16_t,
+            pbuf_type_PBUF_RAM,
+        );
+        if pbuf.is_null() {
+            warn!("netstack tcp held syn: pbuf_alloc null alloc");
+            return;
+        }
+        pbuf_take(
+            pbuf,
+            self.pkt.as_ptr() as *const raw::c_void,
+            self.pkt.len() as u16_t,
+        );
+        let input_fn = match (*netif).input {
+            Some(input_fn) => input_fn,
+            None => {
+                pbuf_free(pbuf);
+                return;
+            }
+        };
+        VERDICT.with(|v| v.set(Some(verdict)));
+        if input_fn(pbuf, netif) != err_enum_t_ERR_OK as err_t {
+            pbuf_free(pbuf);
+        }
+        // The listener may be gone, in which case lwIP resets the SYN itself.
+        VERDICT.with(|v| v.take());
+    }
+}
+
+impl Drop for SynRequest {
+    fn drop(&mut self) {
+        self.held.lock().unwrap().remove(&self.key());
+    }
+}
+
+/// Rebuilds the IP packet carrying the SYN, as it arrived.
+unsafe fn copy_syn(
+    ipv4: bool,
+    hdr: &tcp_hdr,
+    raw_hdr: *const u8,
+    optlen: u16_t,
+    opt1len: u16_t,
+    opt2: *const u8_t,
+    p: *mut pbuf,
+) -> Vec<u8> {
+    let ip_hdr = if ipv4 {
+        ip_data.current_ip4_header as *const u8
+    } else {
+        ip_data.current_ip6_header as *const u8
+    };
+    let ip_hlen = ip_data.current_ip_header_tot_len as usize;
+    let tot_len = std::ptr::read_unaligned(p).tot_len;
+    let mut pkt =
+        Vec::with_capacity(ip_hlen + TCP_HLEN as usize + optlen as usize + tot_len as usize);
+    pkt.extend_from_slice(std::slice::from_raw_parts(ip_hdr, ip_hlen));
+    // tcp_input() converted some header fields to host byte order in place.
+    pkt.extend_from_slice(&{ hdr.src }.to_be_bytes());
+    pkt.extend_from_slice(&{ hdr.dest }.to_be_bytes());
+    pkt.extend_from_slice(&{ hdr.seqno }.to_be_bytes());
+    pkt.extend_from_slice(&{ hdr.ackno }.to_be_bytes());
+    pkt.extend_from_slice(&{ hdr._hdrlen_rsvd_flags }.to_ne_bytes());
+    pkt.extend_from_slice(&{ hdr.wnd }.to_be_bytes());
+    pkt.extend_from_slice(&{ hdr.chksum }.to_ne_bytes());
+    pkt.extend_from_slice(&{ hdr.urgp }.to_ne_bytes());
+    pkt.extend_from_slice(std::slice::from_raw_parts(
+        raw_hdr.add(TCP_HLEN as usize),
+        opt1len as usize,
+    ));
+    if !opt2.is_null() {
+        pkt.extend_from_slice(std::slice::from_raw_parts(
+            opt2,
+            (optlen - opt1len) as usize,
+        ));
+    }
+    let off = pkt.len();
+    pkt.resize(off + tot_len as usize, 0);
+    pbuf_copy_partial(p, pkt[off..].as_mut_ptr() as *mut raw::c_void, tot_len, 0);
+    pkt
+}
+
+/// Overwrites the options of a SYN which the listener was configured not to
+/// negotiate with NOPs, before lwIP parses them.
+unsafe fn strip_syn_options(
+    config: &TcpConfig,
+    hdr: *mut tcp_hdr,
+    optlen: u16_t,
+    opt1len: u16_t,
+    opt2: *mut u8_t,
+) {
+    if config.window_scaling && config.sack {
+        return;
+    }
+    // Options may be split between the first pbuf, right after the header,
+    // and the next one.
+    let opt = |i: u16_t| -> *mut u8 {
+        if i < opt1len {
+            (hdr as *mut u8).add(TCP_HLEN as usize + i as usize)
+        } else {
+            opt2.add((i - opt1len) as usize)
+        }
+    };
+    let mut i = 0;
+    while i < optlen {
+        let kind = *opt(i) as u32;
+        match kind {
+            LWIP_TCP_OPT_EOL => return,
+            LWIP_TCP_OPT_NOP => i += 1,
+            _ => {
+                if i + 1 >= optlen {
+                    return;
+                }
+                let len = (*opt(i + 1) as u16_t).max(2);
+                let strip = match kind {
+                    LWIP_TCP_OPT_WS => !config.window_scaling,
+                    LWIP_TCP_OPT_SACK_PERM => !config.sack,
+                    _ => false,
+                };
+                if strip {
+                    for j in i..(i + len).min(optlen) {
+                        *opt(j) = LWIP_TCP_OPT_NOP as u8;
+                    }
+                }
+                i += len;
+            }
+        }
+    }
+}
+
+/// Called by lwIP for every TCP segment before it is handed to a pcb, see
+/// `LWIP_HOOK_TCP_INPACKET_PCB` in lwiphooks.h.
+///
+/// Runs the pre-accept filter on SYNs hitting a listening pcb. Returning
+/// anything but `ERR_OK` makes lwIP drop the segment.
+#[no_mangle]
+pub unsafe extern "C" fn netstack_tcp_inpacket_hook(
+    pcb: *mut tcp_pcb,
+    hdr: *mut tcp_hdr,
+    optlen: u16_t,
+    opt1len: u16_t,
+    opt2: *mut u8_t,
+    p: *mut pbuf,
+) -> err_t {
+    let lpcb = std::ptr::read_unaligned(pcb as *const tcp_pcb_listen);
+    if lpcb.state != tcp_state_LISTEN || lpcb.callback_arg.is_null() {
+        return err_enum_t_ERR_OK as err_t;
+    }
+    let hdr_v = std::ptr::read_unaligned(hdr);
+    let flags = (u16::from_be(hdr_v._hdrlen_rsvd_flags) & 0x3f) as u32;
+    if flags & (TCP_SYN | TCP_ACK | TCP_RST) != TCP_SYN {
+        return err_enum_t_ERR_OK as err_t;
+    }
+    let listener = &mut *(lpcb.callback_arg as *mut TcpListener);
+    let src_ip = ip_data.current_iphdr_src;
+    let dest_ip = ip_data.current_iphdr_dest;
+    let local_addr = util::to_socket_addr(&src_ip, hdr_v.src);
+    let remote_addr = util::to_socket_addr(&dest_ip, hdr_v.dest);
+
+    let verdict = match VERDICT.with(|v| v.take()) {
+        // A held SYN being fed back.
+        Some(verdict) => verdict,
+        None => {
+            let filter = match listener.filter.as_mut() {
+                Some(filter) => filter,
+                None => {
+                    strip_syn_options(&listener.config, hdr, optlen, opt1len, opt2);
+                    listener.make_room(lpcb.netif_idx);
+                    return err_enum_t_ERR_OK as err_t;
+                }
+            };
+            let key = (local_addr, remote_addr);
+            if !listener.held.lock().unwrap().insert(key) {
+                trace!(
+                    "netstack tcp syn {} -> {} still held",
+                    local_addr,
+                    remote_addr
+                );
+                return err_enum_t_ERR_INPROGRESS as err_t;
+            }
+            let request = SynRequest {
+                local_addr,
+                remote_addr,
+                netif_idx: (*ip_data.current_input_netif).num + 1,
+                seqno: hdr_v.seqno,
+                pkt: copy_syn(
+                    local_addr.is_ipv4(),
+                    &hdr_v,
+                    hdr as *const u8,
+                    optlen,
+                    opt1len,
+                    opt2,
+                    p,
+                ),
+                held: listener.held.clone(),
+            };
+            FILTERING.with(|f| f.set(Some(key)));
+            filter(request);
+            FILTERING.with(|f| f.set(None));
+            match VERDICT.with(|v| v.take()) {
+                Some(verdict) => verdict,
+                None => return err_enum_t_ERR_INPROGRESS as err_t,
+            }
+        }
+    };
+    trace!(
+        "netstack tcp syn {} -> {}: {:?}",
+        local_addr,
+        remote_addr,
+        verdict
+    );
+    match verdict {
+        Verdict::Accept => {
+            // Only once the SYN has been copied, whose checksum covers the
+            // options as they arrived.
+            strip_syn_options(&listener.config, hdr, optlen, opt1len, opt2);
+            listener.make_room(lpcb.netif_idx);
+            return err_enum_t_ERR_OK as err_t;
+        }
+        Verdict::Reset => {
+            let mut tcplen = std::ptr::read_unaligned(p).tot_len as u32 + 1;
+            if flags & TCP_FIN != 0 {
+                tcplen += 1;
+            }
+            tcp_rst(
+                pcb,
+                0,
+                hdr_v.seqno.wrapping_add(tcplen),
+                &dest_ip,
+                &src_ip,
+                hdr_v.dest,
+                hdr_v.src,
+            );
+        }
+        Verdict::Unreachable(reason) => {
+            let mut tcp_hdr = [0u8; 8];
+            tcp_hdr[0..2].copy_from_slice(&{ hdr_v.src }.to_be_bytes());
+            tcp_hdr[2..4].copy_from_slice(&{ hdr_v.dest }.to_be_bytes());
+            tcp_hdr[4..8].copy_from_slice(&{ hdr_v.seqno }.to_be_bytes());
+            if let Err(e) = icmp::output_unreachable(
+                ip_data.current_input_netif,
+                reason,
+                IP_PROTO_TCP as u8,
+                &local_addr,
+                &remote_addr,
+                tcp_hdr,
+            ) {
+                warn!("netstack tcp unreachable failed: {}", e);
+            }
+        }
+    }
+    err_enum_t_ERR_ABRT as err_t
+}
+
 #[allow(unused_variables)]
 pub extern "C" fn tcp_accept_cb(arg: *mut raw::c_void, newpcb: *mut tcp_pcb, err: err_t) -> err_t {
     if arg.is_null() {
@@ -28,8 +397,8 @@ pub extern "C" fn tcp_accept_cb(arg: *mut raw::c_void, newpcb: *mut tcp_pcb, err
         return err_enum_t_ERR_OK as err_t;
     }
     let listener = unsafe { &mut *(arg as *mut TcpListener) };
-    let stream = TcpStream::new(newpcb);
-    let _ = listener.sender.send(stream);
+    let stream = TcpStream::new(newpcb, listener.config);
+    let _ = listener.sender.unbounded_send(stream);
     err_enum_t_ERR_OK as err_t
 }
 
@@ -37,52 +406,112 @@ pub struct TcpListener {
     tpcb: usize,
     pub sender: UnboundedSender<Pin<Box<TcpStream>>>,
     pub receiver: UnboundedReceiver<Pin<Box<TcpStream>>>,
+    filter: Option<Box<dyn FnMut(SynRequest) + Send>>,
+    on_evict: Option<Box<dyn FnMut(Eviction) + Send>>,
+    held: Arc<Mutex<HashSet<FlowKey>>>,
+    config: TcpConfig,
     _pin: PhantomPinned,
 }
 
 impl TcpListener {
-    pub fn new() -> Result<Pin<Box<Self>>, Error> {
-        unsafe {
-            let _g = LWIP_MUTEX.lock();
+    /// Listens for the connections of the stack of `netif` only.
+    pub(crate) fn new(
+        netif: usize,
+        backlog: u8,
+        config: TcpConfig,
+    ) -> Result<Pin<Box<Self>>, Error> {
+        let (sender, receiver) = unbounded();
+        let mut listener = Box::pin(TcpListener {
+            tpcb: 0,
+            sender,
+            receiver,
+            filter: None,
+            on_evict: None,
+            held: Arc::new(Mutex::new(HashSet::new())),
+            config,
+            _pin: PhantomPinned,
+        });
+        let arg = &*listener as *const TcpListener as usize;
+        let tpcb = driver::call(|| unsafe {
             let mut tpcb = tcp_new();
+            tcp_bind_netif(tpcb, netif as *const netif);
             let err = tcp_bind(tpcb, &ip_addr_any_type, 0);
             if err != err_enum_t_ERR_OK as err_t {
                 error!("bind TCP failed: {}", err);
-                return Err(Error::LwIP(err));
+                tcp_close(tpcb);
+                return Err(Error::from_err(err));
             }
             let mut reason: err_t = 0;
-            tpcb = tcp_listen_with_backlog_and_err(
-                tpcb,
-                TCP_DEFAULT_LISTEN_BACKLOG as u8,
-                &mut reason,
-            );
+            tpcb = tcp_listen_with_backlog_and_err(tpcb, backlog, &mut reason);
             if tpcb.is_null() {
                 error!("listen TCP failed: {}", reason);
-                return Err(Error::LwIP(reason));
+                return Err(Error::from_err(reason));
             }
-            let (sender, receiver) = unbounded_channel();
-            let listener = Box::pin(TcpListener {
-                tpcb: tpcb as usize,
-                sender,
-                receiver,
-                _pin: PhantomPinned::default(),
-            });
-            let arg = &*listener as *const TcpListener as *mut raw::c_void;
-            tcp_arg(tpcb, arg);
+            tcp_arg(tpcb, arg as *mut raw::c_void);
             tcp_accept(tpcb, Some(tcp_accept_cb));
-            Ok(listener)
+            Ok(tpcb as usize)
+        })?;
+        unsafe { listener.as_mut().get_unchecked_mut() }.tpcb = tpcb;
+        Ok(listener)
+    }
+
+    /// Installs a filter deciding on incoming connections before the
+    /// handshake completes.
+    ///
+    /// The filter is called with every new SYN, and runs on the thread
+    /// driving lwIP, so it must not block. It can resolve the request right away,
+    /// or hold it and resolve it later, see `SynRequest`. Without a filter,
+    /// every connection is accepted.
+    pub fn set_syn_filter<F>(self: Pin<&mut Self>, filter: F)
+    where
+        F: FnMut(SynRequest) + Send + 'static,
+    {
+        let me = unsafe { self.get_unchecked_mut() } as *mut TcpListener as usize;
+        driver::call(move || unsafe {
+            (*(me as *mut TcpListener)).filter = Some(Box::new(filter));
+        });
+    }
+
+    /// Installs a callback told about the connections evicted to make room
+    /// for new ones, see `NetStackBuilder::pcb_eviction`.
+    ///
+    /// Like the SYN filter, the callback runs on the thread driving lwIP and
+    /// must not block.
+    pub fn set_eviction_callback<F>(self: Pin<&mut Self>, callback: F)
+    where
+        F: FnMut(Eviction) + Send + 'static,
+    {
+        let me = unsafe { self.get_unchecked_mut() } as *mut TcpListener as usize;
+        driver::call(move || unsafe {
+            (*(me as *mut TcpListener)).on_evict = Some(Box::new(callback));
+        });
+    }
+
+    /// Evicts a connection if lwIP has no pcb left for the one being
+    /// accepted, on the driver thread.
+    unsafe fn make_room(&mut self, netif_idx: u8) {
+        if !self.config.pcb_eviction || netstack_tcp_pcb_pool_full() == 0 {
+            return;
+        }
+        match evict_pcb(netif_idx) {
+            Some(eviction) => {
+                if let Some(callback) = self.on_evict.as_mut() {
+                    callback(eviction);
+                }
+            }
+            None => warn!("tcp full, nothing to evict"),
         }
     }
 }
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
-        unsafe {
-            let _g = LWIP_MUTEX.lock();
-            tcp_arg(self.tpcb as *mut tcp_pcb, null_mut());
-            tcp_accept(self.tpcb as *mut tcp_pcb, None);
-            tcp_close(self.tpcb as *mut tcp_pcb);
-        }
+        let tpcb = self.tpcb;
+        driver::call(|| unsafe {
+            tcp_arg(tpcb as *mut tcp_pcb, null_mut());
+            tcp_accept(tpcb as *mut tcp_pcb, None);
+            tcp_close(tpcb as *mut tcp_pcb);
+        });
     }
 }
 
@@ -91,12 +520,12 @@ impl Stream for TcpListener {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let me = unsafe { self.get_unchecked_mut() };
-        match me.receiver.poll_recv(cx) {
+        match me.receiver.poll_next_unpin(cx) {
             Poll::Ready(Some(stream)) => {
                 let local_addr = stream.local_addr().to_owned();
                 let remote_addr = stream.remote_addr().to_owned();
-                return Poll::Ready(Some((stream, local_addr, remote_addr)));
-            },
+                Poll::Ready(Some((stream, local_addr, remote_addr)))
+            }
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Pending => Poll::Pending,
         }
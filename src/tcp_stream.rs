@@ -1,18 +1,22 @@
 use std::marker::PhantomPinned;
-use std::{cmp::min, io, net::SocketAddr, os::raw, pin::Pin};
+use std::sync::Arc;
+use std::{cmp::min, io, net::SocketAddr, os::raw, pin::Pin, time::Duration};
 
-use bytes::BytesMut;
+use bytes::{Buf, Bytes};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::io::{AsyncRead, AsyncWrite};
 use futures::task::{Context, Poll};
+use futures::StreamExt;
 use log::*;
-use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
-    sync::mpsc::unbounded_channel,
-};
 
+use super::builder::{Keepalive, TcpConfig};
+use super::driver;
+use super::icmp::{self, Unreachable};
 use super::lwip::*;
-use super::tcp_stream_context::TcpStreamContext;
+use super::pbuf::pbuf_to_bytes;
+use super::tcp_stream_context::{TcpStreamContext, TcpStreamContextInner};
 use super::util;
-use super::LWIP_MUTEX;
+use crate::Error;
 
 #[allow(unused_variables)]
 pub unsafe extern "C" fn tcp_recv_cb(
@@ -26,24 +30,27 @@ pub unsafe extern "C" fn tcp_recv_cb(
         return err_enum_t_ERR_CONN as err_t;
     }
 
-    // SAFETY: tcp_recv_cb is called from tcp_input or sys_check_timeouts only when
-    // a data packet or previously refused data is received. Thus lwip_mutex must be locked.
-    // See also `<NetStackImpl as AsyncWrite>::poll_write`.
-    let ctx = &mut *TcpStreamContext::assume_locked(arg as *const TcpStreamContext);
+    let ctx = &mut *(*(arg as *const TcpStreamContext)).lock();
 
     if p.is_null() {
         trace!("netstack tcp eof {}", ctx.local_addr);
-        ctx.read_tx.as_ref().map(|tx| tx.send(Vec::new()));
+        ctx.read_tx
+            .as_ref()
+            .map(|tx| tx.unbounded_send(Bytes::new()));
         return err_enum_t_ERR_OK as err_t;
     }
 
-    let pbuflen = std::ptr::read_unaligned(p).tot_len;
-    let mut buf = Vec::with_capacity(pbuflen as usize);
-    pbuf_copy_partial(p, buf.as_mut_ptr() as _, pbuflen, 0);
-    buf.set_len(pbuflen as usize);
+    if ctx.dropped {
+        // Nobody reads anymore, keep the window open until the pcb is closed.
+        tcp_recved(tpcb, std::ptr::read_unaligned(p).tot_len);
+        pbuf_free(p);
+        return err_enum_t_ERR_OK as err_t;
+    }
+
+    let buf = pbuf_to_bytes(p);
 
     if !buf.is_empty() {
-        ctx.read_tx.as_ref().map(|tx| tx.send(buf));
+        ctx.read_tx.as_ref().map(|tx| tx.unbounded_send(buf));
     }
 
     pbuf_free(p);
@@ -52,37 +59,86 @@ pub unsafe extern "C" fn tcp_recv_cb(
 
 #[allow(unused_variables)]
 pub extern "C" fn tcp_sent_cb(arg: *mut raw::c_void, tpcb: *mut tcp_pcb, len: u16_t) -> err_t {
-    // SAFETY: tcp_sent_cb is called from tcp_input only when
-    // an ACK packet is received. Thus lwip_mutex must be locked.
-    // See also `<NetStackImpl as AsyncWrite>::poll_write`.
-    let ctx = &*unsafe { TcpStreamContext::assume_locked(arg as *const TcpStreamContext) };
-    // trace!("netstack tcp sent {}", &ctx.local_addr);
-    if let Some(waker) = ctx.write_waker.as_ref() {
-        waker.wake_by_ref();
-    }
+    let ctx = arg as *const TcpStreamContext;
+    let release = {
+        let inner = &mut *unsafe { &*ctx }.lock();
+        // trace!("netstack tcp sent {}", &inner.local_addr);
+        inner.send_credit += len as usize;
+        unsafe { write_unsent(tpcb, inner) };
+        inner.wake_writer();
+        Release::of(inner)
+    };
+    unsafe { release.apply(tpcb, ctx) }
+}
+
+#[allow(unused_variables)]
+pub extern "C" fn tcp_connected_cb(arg: *mut raw::c_void, tpcb: *mut tcp_pcb, err: err_t) -> err_t {
+    let ctx = &mut *unsafe { &*(arg as *const TcpStreamContext) }.lock();
+    trace!("netstack tcp connected {}", &ctx.local_addr);
+    ctx.connected = true;
+    ctx.wake_writer();
     err_enum_t_ERR_OK as err_t
 }
 
 #[allow(unused_variables)]
 pub extern "C" fn tcp_err_cb(arg: *mut ::std::os::raw::c_void, err: err_t) {
-    // SAFETY: tcp_err_cb is called from
-    // tcp_input, tcp_abandon, tcp_abort, tcp_alloc and tcp_new.
-    // Thus lwip_mutex must be locked before calling any of these.
-    let ctx = &mut *unsafe { TcpStreamContext::assume_locked(arg as *const TcpStreamContext) };
-    trace!("netstack tcp err {} {}", err, ctx.local_addr);
-    ctx.errored = true;
-    let _ = ctx.read_tx.take();
-    if let Some(waker) = ctx.write_waker.as_ref() {
-        waker.wake_by_ref();
+    let ctx = arg as *const TcpStreamContext;
+    {
+        let inner = &mut *unsafe { &*ctx }.lock();
+        trace!(
+            "netstack tcp err {} {} -> {}",
+            err,
+            inner.local_addr,
+            inner.remote_addr
+        );
+        inner.errored = true;
+        // Why lwIP freed the pcb: ERR_RST, ERR_ABRT, ERR_CLSD, or ERR_TIMEOUT from
+        // its timers and the idle reaper, which sets `idle` first.
+        inner.err = err;
+        let _ = inner.read_tx.take();
+        inner.wake_writer();
     }
+    // The pcb is gone, and so is its reference to the context.
+    unsafe { Arc::decrement_strong_count(ctx) };
+}
+
+/// Fails the stream of a pcb aborted by the idle reaper, on the driver
+/// thread. `arg` is the callback argument of the pcb, which is freed already.
+pub(crate) unsafe fn tcp_idle_timeout(arg: *mut raw::c_void) {
+    unsafe { &*(arg as *const TcpStreamContext) }.lock().idle = true;
+    tcp_err_cb(arg, err_enum_t_ERR_TIMEOUT as err_t);
 }
 
 #[allow(unused_variables)]
 pub extern "C" fn tcp_poll_cb(arg: *mut ::std::os::raw::c_void, tpcb: *mut tcp_pcb) -> err_t {
-    let ctx = &*unsafe { TcpStreamContext::assume_locked(arg as *const TcpStreamContext) };
-    // trace!("netstack tcp poll {}", &ctx.local_addr);
-    if let Some(waker) = ctx.write_waker.as_ref() {
-        waker.wake_by_ref();
+    let ctx = arg as *const TcpStreamContext;
+    let release = {
+        let inner = &mut *unsafe { &*ctx }.lock();
+        // trace!("netstack tcp poll {}", &inner.local_addr);
+        unsafe { write_unsent(tpcb, inner) };
+        inner.wake_writer();
+        Release::of(inner)
+    };
+    unsafe { release.apply(tpcb, ctx) }
+}
+
+/// Polls a pcb left behind by a lingering `TcpStream`, `arg` holds the
+/// `tcp_ticks` value after which it is reset.
+#[allow(unused_variables)]
+pub extern "C" fn tcp_linger_cb(arg: *mut ::std::os::raw::c_void, tpcb: *mut tcp_pcb) -> err_t {
+    unsafe {
+        let state = std::ptr::read_unaligned(tpcb).state;
+        if state == tcp_state_FIN_WAIT_2 || state == tcp_state_TIME_WAIT {
+            // Our FIN has been acknowledged.
+            tcp_poll(tpcb, None, 0);
+            return err_enum_t_ERR_OK as err_t;
+        }
+        let deadline = arg as usize as u32;
+        if tcp_ticks.wrapping_sub(deadline) as i32 >= 0 {
+            trace!("netstack tcp linger timeout");
+            tcp_abort(tpcb);
+            return err_enum_t_ERR_ABRT as err_t;
+        }
     }
     err_enum_t_ERR_OK as err_t
 }
@@ -91,14 +147,17 @@ pub struct TcpStream {
     src_addr: SocketAddr,
     dest_addr: SocketAddr,
     pcb: usize,
-    write_buf: BytesMut,
-    callback_ctx: TcpStreamContext,
+    netif_idx: u8,
+    read_buf: Bytes,
+    read_rx: UnboundedReceiver<Bytes>,
+    callback_ctx: Arc<TcpStreamContext>,
     is_eof: bool,
     _pin: PhantomPinned,
 }
 
 impl TcpStream {
-    pub fn new(pcb: *mut tcp_pcb) -> Pin<Box<Self>> {
+    /// Takes over a pcb, on the driver thread.
+    pub(crate) fn new(pcb: *mut tcp_pcb, config: TcpConfig) -> Pin<Box<Self>> {
         unsafe {
             // Since we have no idea how to deal with a full bounded channel upon receiving
             // data from lwIP, an unbounded channel is used instead.
@@ -107,41 +166,175 @@ impl TcpStream {
             // lwIP will propagate the pressure back by announcing a decreased window size.
             // Thus our unbounded channel will never be overwhelmed. To achieve this, we must
             // call `tcp_recved` when the data from our internal buffer are consumed.
-            let (read_tx, read_rx) = unbounded_channel();
+            let (read_tx, read_rx) = unbounded();
+            apply_pcb_opts(pcb, config);
             let pcb_v = std::ptr::read_unaligned(pcb);
             let src_addr = util::to_socket_addr(&pcb_v.remote_ip, pcb_v.remote_port);
             let dest_addr = util::to_socket_addr(&pcb_v.local_ip, pcb_v.local_port);
-            let stream = Box::pin(TcpStream {
+            let callback_ctx = Arc::new(TcpStreamContext::new(
                 src_addr,
                 dest_addr,
-                pcb: pcb as usize,
-                write_buf: BytesMut::new(),
-                callback_ctx: TcpStreamContext::new(src_addr, dest_addr, read_tx, read_rx),
-                is_eof: false,
-                _pin: PhantomPinned::default(),
-            });
-            let arg = &stream.callback_ctx as *const _;
-            tcp_arg(pcb, arg as *mut raw::c_void);
+                read_tx,
+                pcb_v.snd_buf as usize,
+            ));
+            // The pcb holds a reference until lwIP frees it or the stream lets go.
+            tcp_arg(pcb, Arc::into_raw(callback_ctx.clone()) as *mut raw::c_void);
             tcp_recv(pcb, Some(tcp_recv_cb));
             tcp_sent(pcb, Some(tcp_sent_cb));
             tcp_err(pcb, Some(tcp_err_cb));
             tcp_poll(pcb, Some(tcp_poll_cb), 8 as _);
-            stream.apply_pcb_opts();
-            trace!("netstack tcp new {}", stream.local_addr());
-            stream
+            trace!("netstack tcp new {}", src_addr);
+            Box::pin(TcpStream {
+                src_addr,
+                dest_addr,
+                pcb: pcb as usize,
+                netif_idx: pcb_v.netif_idx,
+                read_buf: Bytes::new(),
+                read_rx,
+                callback_ctx,
+                is_eof: false,
+                _pin: PhantomPinned,
+            })
         }
     }
 
-    fn apply_pcb_opts(&self) {
-        unsafe {
-            let mut pcb_v = std::ptr::read_unaligned(self.pcb as *const tcp_pcb);
-            #[cfg(target_os = "ios")]
-            {
-                pcb_v.so_options |= SOF_KEEPALIVE as u8;
+    /// Opens a connection from `src` to `dst` on the TUN side of the netif.
+    pub(crate) async fn connect(
+        netif: usize,
+        config: TcpConfig,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> io::Result<Pin<Box<Self>>> {
+        if src.is_ipv4() != dst.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mismatched address families",
+            ));
+        }
+        let stream = driver::call(|| unsafe {
+            let ip_type = if src.is_ipv4() {
+                lwip_ip_addr_type_IPADDR_TYPE_V4
+            } else {
+                lwip_ip_addr_type_IPADDR_TYPE_V6
+            };
+            let pcb = tcp_new_ip_type(ip_type as u8_t);
+            if pcb.is_null() {
+                return Err(Error::OutOfMemory.into());
+            }
+            tcp_bind_netif(pcb, netif as *const netif);
+            let src_ip = util::to_ip_addr_t(src.ip());
+            let err = tcp_bind(pcb, &src_ip, src.port());
+            if err != err_enum_t_ERR_OK as err_t {
+                tcp_close(pcb);
+                return Err(match Error::from_err(err) {
+                    Error::AddressInUse => Error::AddressInUse.into(),
+                    _ => io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("netstack tcp_bind error {}", err),
+                    ),
+                });
             }
-            pcb_v.flags |= TF_NODELAY as u16;
-            std::ptr::write_unaligned(self.pcb as *mut tcp_pcb, pcb_v);
+            let dst_ip = util::to_ip_addr_t(dst.ip());
+            let err = tcp_connect(pcb, &dst_ip, dst.port(), Some(tcp_connected_cb));
+            if err != err_enum_t_ERR_OK as err_t {
+                tcp_abort(pcb);
+                return Err(Error::from_err(err).into());
+            }
+            Ok(TcpStream::new(pcb, config))
+        })?;
+        futures::future::poll_fn(|cx| stream.poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    fn poll_connected(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let ctx = &mut *self.callback_ctx.lock();
+        if ctx.errored {
+            if ctx.err == err_enum_t_ERR_RST as err_t {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "connection refused",
+                )));
+            }
+            return Poll::Ready(Err(conn_error(ctx)));
+        }
+        if ctx.connected {
+            return Poll::Ready(Ok(()));
         }
+        ctx.write_waker.replace(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Updates the pcb on the driver thread, unless lwIP has freed it.
+    fn update_pcb(&self, f: impl FnOnce(&mut tcp_pcb) + Send) -> io::Result<()> {
+        let pcb = self.pcb;
+        let ctx = &self.callback_ctx;
+        driver::call(move || {
+            let ctx = ctx.lock();
+            if ctx.errored {
+                return Err(conn_error(&ctx));
+            }
+            unsafe {
+                let mut pcb_v = std::ptr::read_unaligned(pcb as *const tcp_pcb);
+                f(&mut pcb_v);
+                std::ptr::write_unaligned(pcb as *mut tcp_pcb, pcb_v);
+            }
+            Ok(())
+        })
+    }
+
+    /// Disables Nagle's algorithm if `nodelay` is true, which is the
+    /// default.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.update_pcb(|pcb| {
+            if nodelay {
+                pcb.flags |= TF_NODELAY as tcpflags_t;
+            } else {
+                pcb.flags &= !(TF_NODELAY as tcpflags_t);
+            }
+        })
+    }
+
+    /// Sends keepalive probes after `idle` without traffic, every
+    /// `interval`, and aborts the connection once `count` probes went
+    /// unanswered. lwIP checks this on its 500ms timer.
+    pub fn set_keepalive(&self, idle: Duration, interval: Duration, count: u32) -> io::Result<()> {
+        let keepalive = Keepalive {
+            idle,
+            interval,
+            count,
+        };
+        self.update_pcb(|pcb| enable_keepalive(pcb, keepalive))
+    }
+
+    /// Stops sending keepalive probes.
+    pub fn disable_keepalive(&self) -> io::Result<()> {
+        self.update_pcb(|pcb| pcb.so_options &= !(SOF_KEEPALIVE as u8))
+    }
+
+    /// Sets the TTL, or hop limit, of the packets of this connection.
+    pub fn set_ttl(&self, ttl: u8) -> io::Result<()> {
+        self.update_pcb(|pcb| pcb.ttl = ttl)
+    }
+
+    /// Sets the TOS, or traffic class, of the packets of this connection.
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        self.update_pcb(|pcb| pcb.tos = tos)
+    }
+
+    /// Sets what happens to the connection when the stream is dropped.
+    ///
+    /// By default, a stream that was shut down finishes closing in the
+    /// background, and one that was not is reset. With `Some(timeout)`, the
+    /// stream is always closed gracefully, and reset if its FIN is still
+    /// unacknowledged after `timeout`. `Some(Duration::ZERO)` always resets
+    /// it, dropping any unsent data.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        let ctx = &mut *self.callback_ctx.lock();
+        if ctx.errored {
+            return Err(conn_error(ctx));
+        }
+        ctx.linger = linger;
+        Ok(())
     }
 
     pub fn local_addr(&self) -> &SocketAddr {
@@ -152,56 +345,157 @@ impl TcpStream {
         &self.dest_addr
     }
 
-    fn send_buf_size(&self) -> usize {
-        unsafe { std::ptr::read_unaligned(self.pcb as *const tcp_pcb).snd_buf as usize }
+    /// Sends an ICMP/ICMPv6 destination unreachable message about this
+    /// flow back to the TUN side host.
+    ///
+    /// Most hosts only fail a connection on such an error while it is
+    /// still being established. Dropping the stream resets it with a RST.
+    pub fn send_unreachable(&self, reason: Unreachable) -> io::Result<()> {
+        let pcb = self.pcb;
+        let ctx = &self.callback_ctx;
+        let seq = driver::call(move || {
+            let ctx = ctx.lock();
+            if ctx.errored {
+                return Err(conn_error(&ctx));
+            }
+            Ok(unsafe { std::ptr::read_unaligned(pcb as *const tcp_pcb).rcv_nxt })
+        })?;
+        let mut tcp_hdr = [0u8; 8];
+        tcp_hdr[0..2].copy_from_slice(&self.src_addr.port().to_be_bytes());
+        tcp_hdr[2..4].copy_from_slice(&self.dest_addr.port().to_be_bytes());
+        tcp_hdr[4..8].copy_from_slice(&seq.to_be_bytes());
+        icmp::send_unreachable(
+            self.netif_idx,
+            reason,
+            IP_PROTO_TCP as u8,
+            &self.src_addr,
+            &self.dest_addr,
+            tcp_hdr,
+        )
+    }
+
+    /// Reads the next chunk of received data without copying it. An empty
+    /// chunk means the peer has closed its side of the connection.
+    pub fn poll_read_bytes(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Bytes>> {
+        let me = unsafe { self.get_unchecked_mut() };
+        if let Some(err) = me.read_error() {
+            return Poll::Ready(Err(err));
+        }
+        if !me.read_buf.is_empty() {
+            return Poll::Ready(Ok(std::mem::take(&mut me.read_buf)));
+        }
+        if me.is_eof {
+            return Poll::Ready(Ok(Bytes::new()));
+        }
+        match me.read_rx.poll_next_unpin(cx) {
+            Poll::Ready(Some(data)) => {
+                if data.is_empty() {
+                    me.is_eof = true;
+                } else {
+                    me.consumed(data.len());
+                }
+                Poll::Ready(Ok(data))
+            }
+            Poll::Ready(None) if me.is_eof => Poll::Ready(Ok(Bytes::new())),
+            Poll::Ready(None) => Poll::Ready(Err(conn_error(&me.callback_ctx.lock()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Same as `poll_read_bytes`.
+    pub async fn read_bytes(mut self: Pin<&mut Self>) -> io::Result<Bytes> {
+        futures::future::poll_fn(|cx| self.as_mut().poll_read_bytes(cx)).await
+    }
+
+    fn read_error(&self) -> Option<io::Error> {
+        let ctx = self.callback_ctx.lock();
+        // A connection closed by both sides still has data and EOF to read.
+        if ctx.errored && ctx.err != err_enum_t_ERR_CLSD as err_t {
+            return Some(conn_error(&ctx));
+        }
+        None
+    }
+
+    /// Opens the receive window again by `len` bytes.
+    fn consumed(&self, len: usize) {
+        let ctx = &mut *self.callback_ctx.lock();
+        if !ctx.errored {
+            ctx.recved += len;
+            self.schedule_sync(ctx);
+        }
+    }
+
+    /// Has the driver hand the unsent data, the FIN and the consumed window
+    /// over to lwIP, unless it is about to.
+    fn schedule_sync(&self, ctx: &mut TcpStreamContextInner) {
+        if std::mem::replace(&mut ctx.sync_queued, true) {
+            return;
+        }
+        let pcb = self.pcb;
+        let callback_ctx = self.callback_ctx.clone();
+        driver::submit(move || unsafe { sync(pcb as *mut tcp_pcb, &callback_ctx) });
+    }
+
+    /// Fails writes once lwIP freed the pcb or refused the data.
+    fn write_error(ctx: &TcpStreamContextInner) -> Option<io::Error> {
+        if ctx.errored {
+            return Some(conn_error(ctx));
+        }
+        ctx.write_err.map(|err| Error::from_err(err).into())
     }
 }
 
-fn broken_pipe() -> io::Error {
-    io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")
+/// The error reported once lwIP has freed the pcb, telling why it did.
+fn conn_error(ctx: &TcpStreamContextInner) -> io::Error {
+    if ctx.idle {
+        return Error::IdleTimeout.into();
+    }
+    Error::from_err(ctx.err).into()
 }
 
-impl AsyncRead for TcpStream {
-    fn poll_read(
-        self: Pin<&mut Self>,
+impl TcpStream {
+    /// Reads up to `remaining` bytes, handing them over to `put`. Shared by
+    /// the `AsyncRead` implementations.
+    fn read_into(
+        &mut self,
         cx: &mut Context,
-        buf: &mut ReadBuf,
+        mut remaining: usize,
+        mut put: impl FnMut(&[u8]),
     ) -> Poll<io::Result<()>> {
-        let me = unsafe { self.get_unchecked_mut() };
-        let guard = LWIP_MUTEX.lock();
-        let ctx = &mut *me.callback_ctx.with_lock(&guard);
-        if ctx.errored {
-            return Poll::Ready(Err(broken_pipe()));
+        let me = self;
+        if let Some(err) = me.read_error() {
+            return Poll::Ready(Err(err));
         }
-        if !me.write_buf.is_empty() {
-            let to_read = min(buf.remaining(), me.write_buf.len());
-            let piece = me.write_buf.split_to(to_read);
-            buf.put_slice(&piece[..to_read]);
+        if !me.read_buf.is_empty() {
+            let to_read = min(remaining, me.read_buf.len());
+            put(&me.read_buf.split_to(to_read));
             return Poll::Ready(Ok(()));
         }
         let mut has_read_data = false;
         loop {
-            match Pin::new(&mut ctx.read_rx).poll_recv(cx) {
+            match me.read_rx.poll_next_unpin(cx) {
                 Poll::Ready(Some(data)) => {
                     // EOF
                     if data.is_empty() {
                         me.is_eof = true;
                         return Poll::Ready(Ok(()));
                     }
-                    unsafe { tcp_recved(me.pcb as *mut tcp_pcb, data.len() as u16_t) };
-                    let to_read = min(buf.remaining(), data.len());
-                    buf.put_slice(&data[..to_read]);
+                    me.consumed(data.len());
+                    let to_read = min(remaining, data.len());
+                    put(&data[..to_read]);
+                    remaining -= to_read;
                     has_read_data = true;
                     if to_read < data.len() {
-                        me.write_buf.extend_from_slice(&data[to_read..]);
+                        me.read_buf = data.slice(to_read..);
                         return Poll::Ready(Ok(()));
                     }
                 }
-                Poll::Ready(None) => return Poll::Ready(Err(broken_pipe())),
+                Poll::Ready(None) if me.is_eof => return Poll::Ready(Ok(())),
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(conn_error(&me.callback_ctx.lock())));
+                }
                 Poll::Pending => {
-                    return if has_read_data {
-                        Poll::Ready(Ok(()))
-                    } else if me.is_eof {
+                    return if has_read_data || me.is_eof {
                         Poll::Ready(Ok(()))
                     } else {
                         Poll::Pending
@@ -212,101 +506,312 @@ impl AsyncRead for TcpStream {
     }
 }
 
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = unsafe { self.get_unchecked_mut() };
+        let mut filled = 0;
+        let remaining = buf.len();
+        me.read_into(cx, remaining, |data| {
+            buf[filled..filled + data.len()].copy_from_slice(data);
+            filled += data.len();
+        })
+        .map_ok(|()| filled)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut tokio::io::ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let me = unsafe { self.get_unchecked_mut() };
+        let remaining = buf.remaining();
+        me.read_into(cx, remaining, |data| buf.put_slice(data))
+    }
+}
+
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let guard = LWIP_MUTEX.lock();
-        let ctx = &*self.callback_ctx.with_lock(&guard);
-        trace!("netstack tcp drop {}", &ctx.local_addr);
-        if !ctx.errored {
-            unsafe {
-                tcp_arg(self.pcb as *mut tcp_pcb, std::ptr::null_mut());
-                tcp_recv(self.pcb as *mut tcp_pcb, None);
-                tcp_sent(self.pcb as *mut tcp_pcb, None);
-                tcp_err(self.pcb as *mut tcp_pcb, None);
-                tcp_poll(self.pcb as *mut tcp_pcb, None, 0);
-                if !ctx.closed {
-                    tcp_abort(self.pcb as *mut tcp_pcb);
-                }
+        trace!("netstack tcp drop {}", &self.src_addr);
+        let pcb = self.pcb;
+        let ctx = self.callback_ctx.clone();
+        driver::submit(move || unsafe { close(pcb as *mut tcp_pcb, &ctx) });
+    }
+}
+
+/// Sets the options of a new pcb, nothing has been written to it yet.
+unsafe fn apply_pcb_opts(pcb: *mut tcp_pcb, config: TcpConfig) {
+    let mut pcb_v = std::ptr::read_unaligned(pcb);
+    if let Some(size) = config.send_buffer {
+        // Nothing has been written yet, all of the buffer is free.
+        pcb_v.snd_buf = size.min(tcpwnd_size_t::MAX as usize) as tcpwnd_size_t;
+    }
+    #[cfg(target_os = "ios")]
+    {
+        pcb_v.so_options |= SOF_KEEPALIVE as u8;
+    }
+    if let Some(keepalive) = config.keepalive {
+        enable_keepalive(&mut pcb_v, keepalive);
+    }
+    pcb_v.flags |= TF_NODELAY as u16;
+    std::ptr::write_unaligned(pcb, pcb_v);
+}
+
+fn enable_keepalive(pcb: &mut tcp_pcb, keepalive: Keepalive) {
+    let millis = |d: Duration| d.as_millis().min(u32::MAX as u128) as u32;
+    pcb.so_options |= SOF_KEEPALIVE as u8;
+    pcb.keep_idle = millis(keepalive.idle);
+    pcb.keep_intvl = millis(keepalive.interval);
+    pcb.keep_cnt = keepalive.count;
+}
+
+/// Hands what the stream queued to lwIP, on the driver thread.
+unsafe fn sync(pcb: *mut tcp_pcb, ctx: &TcpStreamContext) {
+    let ctx = &mut *ctx.lock();
+    ctx.sync_queued = false;
+    // Commands of a stream run before the one closing it, the pcb is still
+    // ours unless lwIP freed it.
+    if ctx.errored {
+        return;
+    }
+    while ctx.recved > 0 {
+        let len = ctx.recved.min(u16_t::MAX as usize);
+        tcp_recved(pcb, len as u16_t);
+        ctx.recved -= len;
+    }
+    write_unsent(pcb, ctx);
+    ctx.wake_writer();
+}
+
+/// Bytes the free entries of the send queue can hold. lwIP fails a whole
+/// `tcp_write` needing more segments than that, which never succeeds once a
+/// peer announces a tiny MSS or window.
+fn queue_room(pcb: &tcp_pcb) -> usize {
+    // Segments are sized as in `tcp_write`, with timestamps disabled data
+    // segments carry no options.
+    let half_wnd = (pcb.snd_wnd_max / 2).min(u16_t::MAX as tcpwnd_size_t) as u16_t;
+    let mss = match pcb.mss.min(half_wnd) {
+        0 => pcb.mss,
+        mss => mss,
+    };
+    // Filling up the last queued segment may take an entry too.
+    let free = (TCP_SND_QUEUELEN as usize).saturating_sub(pcb.snd_queuelen as usize + 1);
+    free * mss as usize
+}
+
+/// Writes as much of the unsent data as lwIP takes, then the FIN once the
+/// stream has been shut down and everything was written.
+unsafe fn write_unsent(pcb: *mut tcp_pcb, ctx: &mut TcpStreamContextInner) {
+    let mut written = false;
+    while let Some(data) = ctx.unsent.front_mut() {
+        let pcb_v = std::ptr::read_unaligned(pcb);
+        let len = data
+            .len()
+            .min(pcb_v.snd_buf as usize)
+            .min(queue_room(&pcb_v))
+            .min(u16_t::MAX as usize);
+        if len == 0 {
+            break;
+        }
+        let err = tcp_write(
+            pcb,
+            data.as_ptr() as *const raw::c_void,
+            len as u16_t,
+            TCP_WRITE_FLAG_COPY as u8,
+        );
+        if err == err_enum_t_ERR_MEM as err_t {
+            // Retried from the sent and poll callbacks.
+            break;
+        }
+        if err != err_enum_t_ERR_OK as err_t {
+            warn!("netstack tcp write error {} on {}", err, ctx.local_addr);
+            ctx.write_err = Some(err);
+            ctx.unsent.clear();
+            break;
+        }
+        written = true;
+        data.advance(len);
+        if data.is_empty() {
+            ctx.unsent.pop_front();
+        }
+    }
+    if written {
+        let err = tcp_output(pcb);
+        if err != err_enum_t_ERR_OK as err_t {
+            trace!("netstack tcp output error {} on {}", err, ctx.local_addr);
+        }
+    }
+    if ctx.closed && !ctx.fin_sent && ctx.unsent.is_empty() && ctx.write_err.is_none() {
+        trace!("netstack tcp shutdown {}", &ctx.local_addr);
+        let err = tcp_shutdown(pcb, 0, 1);
+        if err != err_enum_t_ERR_OK as err_t {
+            ctx.write_err = Some(err);
+        }
+        ctx.fin_sent = true;
+    }
+}
+
+/// Closes the pcb of a dropped stream on the driver thread: right away if
+/// it is reset, otherwise once its unsent data and FIN are written.
+unsafe fn close(pcb: *mut tcp_pcb, ctx: &Arc<TcpStreamContext>) {
+    let release = {
+        let inner = &mut *ctx.lock();
+        if inner.errored {
+            return;
+        }
+        inner.dropped = true;
+        match inner.linger {
+            Some(timeout) if timeout.is_zero() => Release::Abort,
+            None if !inner.closed => Release::Abort,
+            linger => {
+                inner.closed = true;
+                inner.deadline = linger.map(|t| tcp_ticks.wrapping_add(util::slow_ticks(t)));
+                write_unsent(pcb, inner);
+                Release::of(inner)
             }
         }
+    };
+    if let Release::Keep = release {
+        // Check the deadline on every tick until the FIN is written.
+        tcp_poll(pcb, Some(tcp_poll_cb), 1);
     }
+    release.apply(pcb, Arc::as_ptr(ctx));
 }
 
-impl AsyncWrite for TcpStream {
-    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
-        let guard = LWIP_MUTEX.lock();
-        let ctx = &mut *self.callback_ctx.with_lock(&guard);
-        if ctx.errored {
-            return Poll::Ready(Err(broken_pipe()));
+/// What happens to the pcb of a dropped stream.
+enum Release {
+    Keep,
+    Close,
+    Abort,
+}
+
+impl Release {
+    fn of(ctx: &TcpStreamContextInner) -> Self {
+        if !ctx.dropped {
+            return Release::Keep;
         }
-        let to_write = buf.len().min(self.send_buf_size());
-        if to_write == 0 {
-            ctx.write_waker.replace(cx.waker().clone());
-            return Poll::Pending;
+        let expired = matches!(ctx.deadline,
+            Some(deadline) if unsafe { tcp_ticks }.wrapping_sub(deadline) as i32 >= 0);
+        if expired || ctx.write_err.is_some() {
+            Release::Abort
+        } else if ctx.fin_sent {
+            Release::Close
+        } else {
+            Release::Keep
         }
-        let err = unsafe {
-            tcp_write(
-                self.pcb as *mut tcp_pcb,
-                buf.as_ptr() as *const raw::c_void,
-                to_write as u16_t,
-                TCP_WRITE_FLAG_COPY as u8,
-            )
-        };
-        if err == err_enum_t_ERR_OK as err_t {
-            // Call output in case of mem err?
-            let err = unsafe { tcp_output(self.pcb as *mut tcp_pcb) };
-            if err == err_enum_t_ERR_OK as err_t {
-                Poll::Ready(Ok(to_write))
-            } else {
-                Poll::Ready(Err(io::Error::new(
-                    io::ErrorKind::Interrupted,
-                    format!("netstack tcp_output error {}", err),
-                )))
+    }
+
+    /// Detaches the pcb from the context, letting lwIP finish closing it or
+    /// resetting it. Returns `ERR_ABRT` if the pcb was aborted, as lwIP
+    /// callbacks must.
+    unsafe fn apply(self, pcb: *mut tcp_pcb, ctx: *const TcpStreamContext) -> err_t {
+        if let Release::Keep = self {
+            return err_enum_t_ERR_OK as err_t;
+        }
+        tcp_arg(pcb, std::ptr::null_mut());
+        tcp_recv(pcb, None);
+        tcp_sent(pcb, None);
+        tcp_err(pcb, None);
+        tcp_poll(pcb, None, 0);
+        let deadline = (*ctx).lock().deadline;
+        Arc::decrement_strong_count(ctx);
+        match (self, deadline) {
+            (Release::Close, Some(deadline)) => {
+                // Our FIN may still be unacknowledged.
+                tcp_arg(pcb, deadline as usize as *mut raw::c_void);
+                tcp_poll(pcb, Some(tcp_linger_cb), 1);
+                err_enum_t_ERR_OK as err_t
+            }
+            (Release::Close, None) => err_enum_t_ERR_OK as err_t,
+            _ => {
+                tcp_abort(pcb);
+                err_enum_t_ERR_ABRT as err_t
             }
-        } else if err == err_enum_t_ERR_MEM as err_t {
-            // trace!("netstack tcp err_mem on {}", &local_addr);
+        }
+    }
+}
+
+impl TcpStream {
+    // Shared by the `AsyncWrite` implementations.
+    fn write_buf(&self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let ctx = &mut *self.callback_ctx.lock();
+        if let Some(err) = Self::write_error(ctx) {
+            return Poll::Ready(Err(err));
+        }
+        if ctx.closed {
+            return Poll::Ready(Err(Error::NotConnected.into()));
+        }
+        let to_write = buf.len().min(ctx.send_credit);
+        if to_write == 0 {
             ctx.write_waker.replace(cx.waker().clone());
-            Poll::Pending
-        } else {
-            Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                format!("netstack tcp_write error {}", err),
-            )))
+            return Poll::Pending;
         }
+        ctx.send_credit -= to_write;
+        ctx.unsent
+            .push_back(Bytes::copy_from_slice(&buf[..to_write]));
+        self.schedule_sync(ctx);
+        Poll::Ready(Ok(to_write))
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
-        let guard = LWIP_MUTEX.lock();
-        if self.callback_ctx.with_lock(&guard).errored {
-            return Poll::Ready(Err(broken_pipe()));
+    fn flush(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let ctx = &mut *self.callback_ctx.lock();
+        if let Some(err) = Self::write_error(ctx) {
+            return Poll::Ready(Err(err));
         }
-        let err = unsafe { tcp_output(self.pcb as *mut tcp_pcb) };
-        if err != err_enum_t_ERR_OK as err_t {
-            Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                format!("netstack tcp_output error {}", err),
-            )))
-        } else {
-            Poll::Ready(Ok(()))
+        if ctx.unsent.is_empty() {
+            return Poll::Ready(Ok(()));
         }
+        ctx.write_waker.replace(cx.waker().clone());
+        self.schedule_sync(ctx);
+        Poll::Pending
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
-        let guard = LWIP_MUTEX.lock();
-        let ctx = &mut *self.callback_ctx.with_lock(&guard);
-        if ctx.errored {
-            return Poll::Ready(Err(broken_pipe()));
+    fn shutdown(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let ctx = &mut *self.callback_ctx.lock();
+        if let Some(err) = Self::write_error(ctx) {
+            return Poll::Ready(Err(err));
         }
-        trace!("netstack tcp shutdown {}", &ctx.local_addr);
-        let err = unsafe { tcp_shutdown(self.pcb as *mut tcp_pcb, 0, 1) };
-        if err != err_enum_t_ERR_OK as err_t {
-            Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                format!("netstack tcp_shutdown tx error {}", err),
-            )))
-        } else {
-            ctx.closed = true;
-            Poll::Ready(Ok(()))
+        if ctx.fin_sent {
+            return Poll::Ready(Ok(()));
         }
+        ctx.closed = true;
+        ctx.write_waker.replace(cx.waker().clone());
+        self.schedule_sync(ctx);
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.write_buf(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.shutdown(cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.write_buf(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.shutdown(cx)
     }
 }
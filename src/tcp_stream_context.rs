@@ -1,97 +1,93 @@
+use bytes::Bytes;
+use futures::channel::mpsc::UnboundedSender;
 use futures::task::Waker;
 use std::{
-    cell::UnsafeCell,
+    collections::VecDeque,
     net::SocketAddr,
-    ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
 };
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use super::LWIPMutexGuard;
+use super::lwip::{err_enum_t_ERR_OK, err_t};
 
 pub struct TcpStreamContextInner {
     pub local_addr: SocketAddr,
     pub remote_addr: SocketAddr,
-    pub read_tx: Option<UnboundedSender<Vec<u8>>>,
-    pub read_rx: UnboundedReceiver<Vec<u8>>,
+    pub read_tx: Option<UnboundedSender<Bytes>>,
     pub errored: bool,
+    pub err: err_t,
+    /// Aborted by the idle reaper, `err` is then `ERR_TIMEOUT` too.
+    pub idle: bool,
+    /// Set once the handshake of a connection opened by the stack is done.
+    pub connected: bool,
+    /// Shut down for writing, the FIN follows the unsent data.
     pub closed: bool,
+    pub fin_sent: bool,
+    pub linger: Option<Duration>,
     pub write_waker: Option<Waker>,
+    /// Bytes `poll_write` may take: the free room of lwIP's send buffer,
+    /// minus what is still unsent.
+    pub send_credit: usize,
+    /// Written to the stream, not yet handed to lwIP.
+    pub unsent: VecDeque<Bytes>,
+    /// Read from the stream, not yet acknowledged to lwIP.
+    pub recved: usize,
+    /// Why lwIP refused data or the FIN, if it did.
+    pub write_err: Option<err_t>,
+    /// A command handing `unsent` and `recved` to lwIP is queued.
+    pub sync_queued: bool,
+    /// The stream is gone, its pcb is closing in the background.
+    pub dropped: bool,
+    /// `tcp_ticks` value after which a dropped stream still closing is reset.
+    pub deadline: Option<u32>,
 }
 
-#[repr(transparent)]
-pub struct TcpStreamContextRef<'a> {
-    ctx: &'a TcpStreamContext,
-}
-
-impl<'a> Deref for TcpStreamContextRef<'a> {
-    type Target = TcpStreamContextInner;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ctx.inner.get() }
-    }
-}
-
-impl<'a> DerefMut for TcpStreamContextRef<'a> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.ctx.inner.get() }
-    }
-}
-
-impl<'a> Drop for TcpStreamContextRef<'a> {
-    fn drop(&mut self) {
-        self.ctx.borrowed.store(false, Ordering::Release);
-    }
-}
-
-/// Context shared by TcpStreamImpl and lwIP callbacks.
+/// Context shared by a `TcpStream` and the lwIP callbacks of its pcb, which
+/// run on the driver thread.
 pub struct TcpStreamContext {
-    inner: UnsafeCell<TcpStreamContextInner>,
-    borrowed: AtomicBool,
+    inner: Mutex<TcpStreamContextInner>,
 }
 
-// Users must hold a lwip_mutex to get the mutable reference to inner data,
-// or go through unsafe interfaces.
-unsafe impl Sync for TcpStreamContext {}
-
 impl TcpStreamContext {
     pub fn new(
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
-        read_tx: UnboundedSender<Vec<u8>>,
-        read_rx: UnboundedReceiver<Vec<u8>>,
+        read_tx: UnboundedSender<Bytes>,
+        send_credit: usize,
     ) -> Self {
         TcpStreamContext {
-            inner: UnsafeCell::new(TcpStreamContextInner {
+            inner: Mutex::new(TcpStreamContextInner {
                 local_addr,
                 remote_addr,
                 read_tx: Some(read_tx),
-                read_rx,
                 errored: false,
+                err: err_enum_t_ERR_OK as err_t,
+                idle: false,
+                connected: false,
                 closed: false,
+                fin_sent: false,
+                linger: None,
                 write_waker: None,
+                send_credit,
+                unsent: VecDeque::new(),
+                recved: 0,
+                write_err: None,
+                sync_queued: false,
+                dropped: false,
+                deadline: None,
             }),
-            borrowed: AtomicBool::new(false),
         }
     }
 
-    /// Access to inner data with lwip_mutex locked.
-    ///
-    /// # Panics
-    ///
-    /// Panics if another reference to inner data exists.
-    pub fn with_lock<'a>(&'a self, _guard: &'a LWIPMutexGuard) -> TcpStreamContextRef<'a> {
-        if self.borrowed.swap(true, Ordering::Acquire) {
-            panic!("TcpStreamContext locked twice within a locked period")
-        }
-        TcpStreamContextRef { ctx: self }
+    pub fn lock(&self) -> MutexGuard<'_, TcpStreamContextInner> {
+        self.inner.lock().unwrap()
     }
+}
 
-    /// Access to inner data within a lwIP callback where lwip_mutex is guaranteed to be locked.
-    ///
-    /// # Panics
-    ///
-    /// Panics if another reference to inner data exists.
-    pub unsafe fn assume_locked<'a>(ptr: *const Self) -> TcpStreamContextRef<'a> {
-        TcpStreamContextRef { ctx: &*ptr }
+impl TcpStreamContextInner {
+    pub fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
     }
 }
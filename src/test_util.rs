@@ -0,0 +1,489 @@
+//! A TUN side host for tests, talking to a `NetStack` through raw packets.
+//!
+//! `Peer` writes IPv4/IPv6 packets into the sink of a stack and reads what
+//! comes out of its stream, playing the client side of TCP connections and
+//! UDP exchanges. No TUN device or privileges are needed to exercise the
+//! stack, its `TcpListener` and its `UdpSocket` end to end.
+//!
+//! It does just enough TCP for tests on a lossless link: segments are
+//! expected in order, nothing is retransmitted, and the stack's window is
+//! not checked.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use super::lwip::{IP_PROTO_TCP, IP_PROTO_UDP};
+use super::util;
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+
+/// Largest payload `Peer` puts in a segment, and the MSS it announces.
+pub const MSS: u16 = 1400;
+
+const TTL: u8 = 64;
+
+/// A TCP segment, addresses included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpSegment {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// Raw options, padded to a multiple of 4 bytes.
+    pub options: Vec<u8>,
+    pub payload: Bytes,
+}
+
+impl TcpSegment {
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+
+    /// Sequence space taken by the segment.
+    pub fn len(&self) -> u32 {
+        self.payload.len() as u32 + self.has(SYN) as u32 + self.has(FIN) as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Builds the IP packet carrying the segment, checksums included.
+    pub fn to_packet(&self) -> Bytes {
+        let hdr_len = 20 + self.options.len();
+        let mut tcp = Vec::with_capacity(hdr_len + self.payload.len());
+        tcp.extend_from_slice(&self.src.port().to_be_bytes());
+        tcp.extend_from_slice(&self.dst.port().to_be_bytes());
+        tcp.extend_from_slice(&self.seq.to_be_bytes());
+        tcp.extend_from_slice(&self.ack.to_be_bytes());
+        tcp.extend_from_slice(&[((hdr_len / 4) as u8) << 4, self.flags]);
+        tcp.extend_from_slice(&self.window.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(&self.options);
+        tcp.extend_from_slice(&self.payload);
+        ip_packet(self.src.ip(), self.dst.ip(), IP_PROTO_TCP as u8, tcp)
+    }
+}
+
+/// A UDP datagram, addresses included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpDatagram {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Bytes,
+}
+
+impl UdpDatagram {
+    /// Builds the IP packet carrying the datagram, checksums included.
+    pub fn to_packet(&self) -> Bytes {
+        let mut udp = Vec::with_capacity(8 + self.payload.len());
+        udp.extend_from_slice(&self.src.port().to_be_bytes());
+        udp.extend_from_slice(&self.dst.port().to_be_bytes());
+        udp.extend_from_slice(&((8 + self.payload.len()) as u16).to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(&self.payload);
+        ip_packet(self.src.ip(), self.dst.ip(), IP_PROTO_UDP as u8, udp)
+    }
+}
+
+/// A packet emitted by the stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Tcp(TcpSegment),
+    Udp(UdpDatagram),
+    /// Anything else, ICMP included.
+    Other(Bytes),
+}
+
+impl Packet {
+    /// Parses an IPv4 or IPv6 packet. IPv6 extension headers are not
+    /// followed, such packets come out as `Other`.
+    pub fn parse(pkt: Bytes) -> Packet {
+        match parse_l4(&pkt) {
+            Some((src, dst, proto, l4)) => {
+                let ports = |l4: &[u8]| {
+                    (
+                        SocketAddr::new(src, u16::from_be_bytes([l4[0], l4[1]])),
+                        SocketAddr::new(dst, u16::from_be_bytes([l4[2], l4[3]])),
+                    )
+                };
+                match proto as u32 {
+                    IP_PROTO_TCP if l4.len() >= 20 => {
+                        let doff = ((l4[12] >> 4) as usize * 4).clamp(20, l4.len());
+                        let (src, dst) = ports(l4);
+                        let offset = l4.as_ptr() as usize - pkt.as_ptr() as usize;
+                        Packet::Tcp(TcpSegment {
+                            src,
+                            dst,
+                            seq: u32::from_be_bytes(l4[4..8].try_into().unwrap()),
+                            ack: u32::from_be_bytes(l4[8..12].try_into().unwrap()),
+                            flags: l4[13],
+                            window: u16::from_be_bytes([l4[14], l4[15]]),
+                            options: l4[20..doff].to_vec(),
+                            payload: pkt.slice(offset + doff..offset + l4.len()),
+                        })
+                    }
+                    IP_PROTO_UDP if l4.len() >= 8 => {
+                        let (src, dst) = ports(l4);
+                        let offset = l4.as_ptr() as usize - pkt.as_ptr() as usize;
+                        Packet::Udp(UdpDatagram {
+                            src,
+                            dst,
+                            payload: pkt.slice(offset + 8..offset + l4.len()),
+                        })
+                    }
+                    _ => Packet::Other(pkt),
+                }
+            }
+            None => Packet::Other(pkt),
+        }
+    }
+}
+
+/// Returns the addresses, the protocol and the transport part of a packet.
+fn parse_l4(pkt: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    match pkt.first()? >> 4 {
+        4 => {
+            let hlen = ((pkt[0] & 0x0f) as usize) * 4;
+            let tot_len = u16::from_be_bytes(pkt.get(2..4)?.try_into().ok()?) as usize;
+            if hlen < 20 || tot_len < hlen || pkt.len() < tot_len {
+                return None;
+            }
+            let src: [u8; 4] = pkt[12..16].try_into().ok()?;
+            let dst: [u8; 4] = pkt[16..20].try_into().ok()?;
+            Some((src.into(), dst.into(), pkt[9], &pkt[hlen..tot_len]))
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes(pkt.get(4..6)?.try_into().ok()?) as usize;
+            if pkt.len() < 40 + payload_len {
+                return None;
+            }
+            let src: [u8; 16] = pkt[8..24].try_into().ok()?;
+            let dst: [u8; 16] = pkt[24..40].try_into().ok()?;
+            Some((src.into(), dst.into(), pkt[6], &pkt[40..40 + payload_len]))
+        }
+        _ => None,
+    }
+}
+
+/// Wraps a TCP or UDP message into an IP packet, filling in its checksum.
+fn ip_packet(src: IpAddr, dst: IpAddr, proto: u8, mut l4: Vec<u8>) -> Bytes {
+    let cksum_at = if proto as u32 == IP_PROTO_TCP { 16 } else { 6 };
+    let len = l4.len();
+    let mut pkt = Vec::with_capacity(40 + len);
+    let cksum = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pkt.extend_from_slice(&[0x45, 0]);
+            pkt.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+            pkt.extend_from_slice(&[0, 0, 0x40, 0, TTL, proto, 0, 0]);
+            pkt.extend_from_slice(&src.octets());
+            pkt.extend_from_slice(&dst.octets());
+            let cksum = util::checksum(&[&pkt]);
+            pkt[10..12].copy_from_slice(&cksum.to_be_bytes());
+            util::checksum(&[
+                &src.octets(),
+                &dst.octets(),
+                &[0, proto],
+                &(len as u16).to_be_bytes(),
+                &l4,
+            ])
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            pkt.extend_from_slice(&[0x60, 0, 0, 0]);
+            pkt.extend_from_slice(&(len as u16).to_be_bytes());
+            pkt.extend_from_slice(&[proto, TTL]);
+            pkt.extend_from_slice(&src.octets());
+            pkt.extend_from_slice(&dst.octets());
+            util::checksum(&[
+                &src.octets(),
+                &dst.octets(),
+                &(len as u32).to_be_bytes(),
+                &[0, 0, 0, proto],
+                &l4,
+            ])
+        }
+        _ => panic!("mismatched address families"),
+    };
+    // A zero UDP checksum means none, its complement is sent instead.
+    let cksum = if cksum == 0 { 0xffff } else { cksum };
+    l4[cksum_at..cksum_at + 2].copy_from_slice(&cksum.to_be_bytes());
+    pkt.extend_from_slice(&l4);
+    pkt.into()
+}
+
+/// The peer side state of a TCP connection opened with `Peer::connect`.
+#[derive(Debug, Clone)]
+pub struct TcpConn {
+    /// The peer's endpoint.
+    pub local: SocketAddr,
+    /// The endpoint the peer connected to, as seen by the stack's listener.
+    pub remote: SocketAddr,
+    /// Next sequence number to send.
+    pub snd_nxt: u32,
+    /// Next sequence number expected from the stack.
+    pub rcv_nxt: u32,
+    /// The stack has sent its FIN.
+    pub fin_received: bool,
+}
+
+impl TcpConn {
+    fn segment(&self, flags: u8, payload: Bytes) -> TcpSegment {
+        TcpSegment {
+            src: self.local,
+            dst: self.remote,
+            seq: self.snd_nxt,
+            ack: self.rcv_nxt,
+            flags,
+            window: u16::MAX,
+            options: Vec::new(),
+            payload,
+        }
+    }
+
+    fn is_ours(&self, seg: &TcpSegment) -> bool {
+        seg.src == self.remote && seg.dst == self.local
+    }
+}
+
+/// A TUN side host writing packets into `sink` and reading `stream`, which
+/// are the two halves of a split `NetStack`.
+///
+/// Packets received while waiting for something else are kept for later
+/// calls.
+pub struct Peer<S, R> {
+    sink: S,
+    stream: R,
+    pending: VecDeque<Packet>,
+    iss: u32,
+}
+
+impl<S, R> Peer<S, R>
+where
+    S: Sink<Bytes> + Unpin,
+    S::Error: Debug,
+    R: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    pub fn new(sink: S, stream: R) -> Self {
+        Peer {
+            sink,
+            stream,
+            pending: VecDeque::new(),
+            iss: 1000,
+        }
+    }
+
+    /// Writes a raw packet into the stack.
+    pub async fn send(&mut self, pkt: Bytes) -> io::Result<()> {
+        self.sink
+            .send(pkt)
+            .await
+            .map_err(|e| io::Error::other(format!("{:?}", e)))
+    }
+
+    /// Reads the next packet emitted by the stack.
+    pub async fn recv(&mut self) -> io::Result<Packet> {
+        if let Some(pkt) = self.pending.pop_front() {
+            return Ok(pkt);
+        }
+        self.recv_stream().await
+    }
+
+    /// Reads the next packet for which `f` returns true, keeping the others.
+    pub async fn recv_matching(
+        &mut self,
+        mut f: impl FnMut(&Packet) -> bool,
+    ) -> io::Result<Packet> {
+        if let Some(i) = self.pending.iter().position(&mut f) {
+            return Ok(self.pending.remove(i).unwrap());
+        }
+        loop {
+            let pkt = self.recv_stream().await?;
+            if f(&pkt) {
+                return Ok(pkt);
+            }
+            self.pending.push_back(pkt);
+        }
+    }
+
+    async fn recv_stream(&mut self) -> io::Result<Packet> {
+        match self.stream.next().await {
+            Some(pkt) => Ok(Packet::parse(pkt?)),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    /// Reads the next segment of `conn` sent by the stack.
+    pub async fn recv_segment(&mut self, conn: &TcpConn) -> io::Result<TcpSegment> {
+        let pkt = self
+            .recv_matching(|p| matches!(p, Packet::Tcp(seg) if conn.is_ours(seg)))
+            .await?;
+        match pkt {
+            Packet::Tcp(seg) => Ok(seg),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Opens a connection from `local` to `remote` through the stack, i.e.
+    /// one its `TcpListener` accepts. Fails with `ConnectionRefused` if the
+    /// stack answers the SYN with a RST.
+    pub async fn connect(&mut self, local: SocketAddr, remote: SocketAddr) -> io::Result<TcpConn> {
+        let mut conn = TcpConn {
+            local,
+            remote,
+            snd_nxt: self.iss,
+            rcv_nxt: 0,
+            fin_received: false,
+        };
+        self.iss = self.iss.wrapping_add(100_000);
+        let mut syn = conn.segment(SYN, Bytes::new());
+        syn.options = [2, 4].into_iter().chain(MSS.to_be_bytes()).collect();
+        self.send(syn.to_packet()).await?;
+        conn.snd_nxt = conn.snd_nxt.wrapping_add(1);
+        let synack = self.recv_segment(&conn).await?;
+        if synack.has(RST) {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        if !synack.has(SYN | ACK) || synack.ack != conn.snd_nxt {
+            return Err(io::Error::other(format!("unexpected {:?}", synack)));
+        }
+        conn.rcv_nxt = synack.seq.wrapping_add(1);
+        self.send(conn.segment(ACK, Bytes::new()).to_packet())
+            .await?;
+        Ok(conn)
+    }
+
+    /// Sends `data` on `conn`, in segments of at most `MSS` bytes. Does not
+    /// wait for them to be acknowledged.
+    pub async fn write(&mut self, conn: &mut TcpConn, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(MSS as usize) {
+            let seg = conn.segment(PSH | ACK, Bytes::copy_from_slice(chunk));
+            self.send(seg.to_packet()).await?;
+            conn.snd_nxt = conn.snd_nxt.wrapping_add(chunk.len() as u32);
+        }
+        Ok(())
+    }
+
+    /// Reads the data of the next segment of `conn` carrying some, and
+    /// acknowledges it. Returns an empty buffer once the stack has sent its
+    /// FIN, and fails with `ConnectionReset` on a RST.
+    pub async fn read(&mut self, conn: &mut TcpConn) -> io::Result<Bytes> {
+        loop {
+            if conn.fin_received {
+                return Ok(Bytes::new());
+            }
+            let seg = self.recv_segment(conn).await?;
+            if seg.has(RST) {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            if seg.is_empty() {
+                continue;
+            }
+            if seg.seq != conn.rcv_nxt {
+                // Out of order or retransmitted, ask for what is missing.
+                self.send(conn.segment(ACK, Bytes::new()).to_packet())
+                    .await?;
+                continue;
+            }
+            conn.rcv_nxt = conn.rcv_nxt.wrapping_add(seg.len());
+            conn.fin_received = seg.has(FIN);
+            self.send(conn.segment(ACK, Bytes::new()).to_packet())
+                .await?;
+            if !seg.payload.is_empty() {
+                return Ok(seg.payload);
+            }
+        }
+    }
+
+    /// Reads from `conn` until the stack sends its FIN.
+    pub async fn read_to_end(&mut self, conn: &mut TcpConn) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        loop {
+            let buf = self.read(conn).await?;
+            if buf.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf);
+        }
+    }
+
+    /// Sends a FIN on `conn`.
+    pub async fn shutdown(&mut self, conn: &mut TcpConn) -> io::Result<()> {
+        self.send(conn.segment(FIN | ACK, Bytes::new()).to_packet())
+            .await?;
+        conn.snd_nxt = conn.snd_nxt.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Sends a RST on `conn`.
+    pub async fn reset(&mut self, conn: &TcpConn) -> io::Result<()> {
+        self.send(conn.segment(RST | ACK, Bytes::new()).to_packet())
+            .await
+    }
+
+    /// Sends a datagram from `src` to `dst` through the stack.
+    pub async fn send_udp(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let datagram = UdpDatagram {
+            src,
+            dst,
+            payload: Bytes::copy_from_slice(data),
+        };
+        self.send(datagram.to_packet()).await
+    }
+
+    /// Reads the next datagram emitted by the stack.
+    pub async fn recv_udp(&mut self) -> io::Result<UdpDatagram> {
+        match self.recv_matching(|p| matches!(p, Packet::Udp(_))).await? {
+            Packet::Udp(datagram) => Ok(datagram),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let seg = TcpSegment {
+            src: "[fd00::1]:1000".parse().unwrap(),
+            dst: "[fd00::2]:80".parse().unwrap(),
+            seq: 1,
+            ack: 2,
+            flags: PSH | ACK,
+            window: 100,
+            options: vec![1, 1, 1, 1],
+            payload: Bytes::from_static(b"hello"),
+        };
+        let pkt = seg.to_packet();
+        assert_eq!(
+            util::checksum(&[&pkt[8..40], &[0, 0, 0, 29, 0, 0, 0, 6], &pkt[40..]]),
+            0
+        );
+        assert_eq!(Packet::parse(pkt), Packet::Tcp(seg));
+        let datagram = UdpDatagram {
+            src: "10.0.0.1:1000".parse().unwrap(),
+            dst: "1.1.1.1:53".parse().unwrap(),
+            payload: Bytes::from_static(b"query"),
+        };
+        let pkt = datagram.to_packet();
+        assert_eq!(util::checksum(&[&pkt[..20]]), 0);
+        assert_eq!(Packet::parse(pkt), Packet::Udp(datagram));
+    }
+}
@@ -1,17 +1,25 @@
-use std::{io, net::SocketAddr, os::raw, pin::Pin};
+use std::collections::HashMap;
 use std::marker::PhantomPinned;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{io, net::SocketAddr, os::raw, pin::Pin};
 
+use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::stream::Stream;
-use futures::task::{Context, Poll, Waker};
+use futures::task::{Context, Poll};
 use futures::StreamExt;
-use log::{error, warn};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use log::{error, trace, warn};
 
+use super::clock;
+use super::driver;
+use super::icmp::{self, Unreachable};
 use super::lwip::*;
+use super::queue::{DropCounter, OverflowPolicy, PacketQueue};
 use super::util;
 use crate::Error;
 
-pub unsafe extern "C" fn udp_recv_cb(
+pub(crate) unsafe extern "C" fn udp_recv_cb(
     arg: *mut raw::c_void,
     _pcb: *mut udp_pcb,
     p: *mut pbuf,
@@ -24,20 +32,66 @@ pub unsafe extern "C" fn udp_recv_cb(
         warn!("udp socket has been closed");
         return;
     }
-    let socket = &mut *(arg as *mut UdpSocket);
+    let socket = &*(arg as *const UdpSocket);
     let src_addr = util::to_socket_addr(&*addr, port);
     let dst_addr = util::to_socket_addr(&*dst_addr, dst_port);
+    let buf = copy_pbuf(p);
+    let _ = socket.queue.push((buf, src_addr, dst_addr), false);
+}
+
+unsafe extern "C" fn udp_listener_recv_cb(
+    arg: *mut raw::c_void,
+    _pcb: *mut udp_pcb,
+    p: *mut pbuf,
+    addr: *const ip_addr_t,
+    port: u16_t,
+    dst_addr: *const ip_addr_t,
+    dst_port: u16_t,
+) {
+    if arg.is_null() {
+        warn!("udp listener has been closed");
+        return;
+    }
+    let listener = &*(arg as *const UdpListener);
+    let src_addr = util::to_socket_addr(&*addr, port);
+    let dst_addr = util::to_socket_addr(&*dst_addr, dst_port);
+    let buf = copy_pbuf(p);
+    let key = (src_addr, dst_addr);
+    let mut flows = listener.shared.flows.lock().unwrap();
+    if let Some((_, tx, last_active)) = flows.get_mut(&key) {
+        *last_active = clock::now();
+        if tx.try_send(buf).is_err() {
+            listener.socket.queue.drops().inc();
+        }
+        return;
+    }
+    let id = listener.shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let (mut tx, rx) = channel(listener.flow_buffer_size);
+    let _ = tx.try_send(buf);
+    flows.insert(key, (id, tx, clock::now()));
+    drop(flows);
+    trace!("netstack udp new flow {} -> {}", src_addr, dst_addr);
+    let flow = UdpFlow {
+        id,
+        local_addr: src_addr,
+        remote_addr: dst_addr,
+        pcb: listener.socket.pcb,
+        netif_idx: listener.socket.netif_idx,
+        rx,
+        shared: listener.shared.clone(),
+    };
+    if listener.tx.lock().unwrap().try_send(flow).is_err() {
+        listener.socket.queue.drops().inc();
+    }
+}
+
+unsafe fn copy_pbuf(p: *mut pbuf) -> Vec<u8> {
     let tot_len = std::ptr::read_unaligned(p).tot_len;
     let mut buf = Vec::with_capacity(tot_len as usize);
     pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0);
     buf.set_len(tot_len as usize);
     pbuf_free(p);
-    if let Err(_) = socket.tx.try_send((buf, src_addr, dst_addr)) {
-        // log::trace!("try send udp pkt failed (netstack): {}", e);
-    }
-    if let Some(waker) = socket.waker.as_ref() {
-        waker.wake_by_ref();
-    }
+    buf
 }
 
 fn send_udp(
@@ -46,75 +100,151 @@ fn send_udp(
     pcb: usize,
     data: &[u8],
 ) -> io::Result<()> {
-    unsafe {
-        let _g = super::LWIP_MUTEX.lock();
-        let pbuf =
-            pbuf_alloc_reference(data.as_ptr() as *mut _, data.len() as _, pbuf_type_PBUF_REF);
-        let src_ip = util::to_ip_addr_t(src_addr.ip());
-        let dst_ip = util::to_ip_addr_t(dst_addr.ip());
-        let err = udp_sendto(
-            pcb as *mut udp_pcb,
-            pbuf,
-            &dst_ip as *const _,
-            dst_addr.port(),
-            &src_ip as *const _,
-            src_addr.port(),
-        );
-        pbuf_free(pbuf);
-        if err != err_enum_t_ERR_OK as err_t {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("udp_sendto error: {}", err),
-            ));
-        }
-        Ok(())
+    driver::call(|| unsafe { output_udp(src_addr, dst_addr, pcb, data) })
+}
+
+/// Same as `send_udp`, for callers already on the driver thread.
+unsafe fn output_udp(
+    src_addr: &SocketAddr,
+    dst_addr: &SocketAddr,
+    pcb: usize,
+    data: &[u8],
+) -> io::Result<()> {
+    let pbuf = pbuf_alloc_reference(data.as_ptr() as *mut _, data.len() as _, pbuf_type_PBUF_REF);
+    let src_ip = util::to_ip_addr_t(src_addr.ip());
+    let dst_ip = util::to_ip_addr_t(dst_addr.ip());
+    let err = udp_sendto(
+        pcb as *mut udp_pcb,
+        pbuf,
+        &dst_ip as *const _,
+        dst_addr.port(),
+        &src_ip as *const _,
+        src_addr.port(),
+    );
+    pbuf_free(pbuf);
+    if err != err_enum_t_ERR_OK as err_t {
+        return Err(Error::from_err(err).into());
     }
+    Ok(())
+}
+
+fn udp_unreachable_hdr(src_addr: &SocketAddr, dst_addr: &SocketAddr) -> [u8; 8] {
+    let mut udp_hdr = [0u8; 8];
+    udp_hdr[0..2].copy_from_slice(&src_addr.port().to_be_bytes());
+    udp_hdr[2..4].copy_from_slice(&dst_addr.port().to_be_bytes());
+    udp_hdr[4..6].copy_from_slice(&8u16.to_be_bytes());
+    udp_hdr
 }
 
 type UdpPkt = (Vec<u8>, SocketAddr, SocketAddr);
 
 pub struct UdpSocket {
     pcb: usize,
-    waker: Option<Waker>,
-    tx: Sender<UdpPkt>,
-    rx: Receiver<UdpPkt>,
-    _pin: PhantomPinned
+    netif_idx: u8,
+    queue: PacketQueue<UdpPkt>,
+    _pin: PhantomPinned,
 }
 
 impl UdpSocket {
-    pub(crate) fn new(buffer_size: usize) -> Result<Pin<Box<Self>>, Error> {
-        unsafe {
+    pub(crate) fn new(
+        netif: usize,
+        buffer_size: usize,
+        drops: DropCounter,
+    ) -> Result<Pin<Box<Self>>, Error> {
+        let mut socket = Box::pin(Self {
+            pcb: 0,
+            netif_idx: unsafe { (*(netif as *const netif)).num } + 1,
+            queue: PacketQueue::new(buffer_size, drops),
+            _pin: PhantomPinned,
+        });
+        let arg = &*socket as *const UdpSocket as usize;
+        let pcb = driver::call(|| unsafe {
             let pcb = udp_new();
-            let (tx, rx): (Sender<UdpPkt>, Receiver<UdpPkt>) = channel(buffer_size);
-            let socket = Box::pin(Self {
-                pcb: pcb as usize,
-                waker: None,
-                tx,
-                rx,
-                _pin: PhantomPinned::default()
-            });
+            udp_bind_netif(pcb, netif as *const netif);
             let err = udp_bind(pcb, &ip_addr_any_type, 0);
             if err != err_enum_t_ERR_OK as err_t {
                 error!("bind UDP failed: {}", err);
-                return Err(Error::LwIP(err));
+                udp_remove(pcb);
+                return Err(Error::from_err(err));
             }
-            let arg = &*socket as *const UdpSocket as *mut raw::c_void;
-            udp_recv(pcb, Some(udp_recv_cb), arg);
-            Ok(socket)
-        }
+            udp_recv(pcb, Some(udp_recv_cb), arg as *mut raw::c_void);
+            Ok(pcb as usize)
+        })?;
+        unsafe { socket.as_mut().get_unchecked_mut() }.pcb = pcb;
+        Ok(socket)
+    }
+
+    /// Sets what happens to datagrams arriving while the receive queue is
+    /// full. `OverflowPolicy::Backpressure` behaves like `DropNewest` here.
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        self.queue.set_policy(policy);
+    }
+
+    /// Counts the datagrams dropped because the receive queue, or the queue
+    /// of a `UdpFlow`, was full.
+    pub fn drops(&self) -> DropCounter {
+        self.queue.drops().clone()
+    }
+
+    /// Turns the socket into a `UdpListener`, which demultiplexes datagrams
+    /// into one `UdpFlow` per (source, destination) pair.
+    ///
+    /// A flow is closed once no datagram went either way for
+    /// `idle_timeout`, checked on the driver thread every quarter of it, at
+    /// most every second.
+    pub fn listen(self: Pin<Box<Self>>, idle_timeout: Duration) -> Pin<Box<UdpListener>> {
+        let buffer_size = self.queue.capacity();
+        let (tx, rx) = channel(buffer_size);
+        let mut listener = Box::pin(UdpListener {
+            socket: self,
+            shared: Arc::new(FlowTable {
+                flows: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                closed: AtomicBool::new(false),
+            }),
+            ticker: 0,
+            flow_buffer_size: buffer_size,
+            tx: Mutex::new(tx),
+            rx,
+            _pin: PhantomPinned,
+        });
+        let pcb = listener.socket.pcb;
+        let arg = &*listener as *const UdpListener as usize;
+        let shared = listener.shared.clone();
+        let period = (idle_timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let ticker = driver::call(|| unsafe {
+            udp_recv(
+                pcb as *mut udp_pcb,
+                Some(udp_listener_recv_cb),
+                arg as *mut raw::c_void,
+            );
+            driver::add_ticker(period, move || shared.expire(idle_timeout))
+        });
+        unsafe { listener.as_mut().get_unchecked_mut() }.ticker = ticker;
+        listener
     }
 
     pub fn split(self: Pin<Box<Self>>) -> (SendHalf, RecvHalf) {
-        (SendHalf { pcb: self.pcb }, RecvHalf { socket: self })
+        (
+            SendHalf {
+                pcb: self.pcb,
+                netif_idx: self.netif_idx,
+            },
+            RecvHalf { socket: self },
+        )
     }
 }
 
 impl Drop for UdpSocket {
     fn drop(&mut self) {
-        unsafe {
-            udp_recv(self.pcb as *mut udp_pcb, None, std::ptr::null_mut());
-            udp_remove(self.pcb as *mut udp_pcb);
+        let pcb = self.pcb;
+        if pcb == 0 {
+            return;
         }
+        driver::call(|| unsafe {
+            udp_recv(pcb as *mut udp_pcb, None, std::ptr::null_mut());
+            udp_remove(pcb as *mut udp_pcb);
+        });
     }
 }
 
@@ -122,21 +252,13 @@ impl Stream for UdpSocket {
     type Item = UdpPkt;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let this = unsafe { self.get_unchecked_mut() };
-
-        match this.rx.poll_recv(cx) {
-            Poll::Ready(Some(pkt)) => Poll::Ready(Some(pkt)),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => {
-                this.waker.replace(cx.waker().clone());
-                Poll::Pending
-            }
-        }
+        self.queue.poll_pop(cx).map(|(pkt, _)| Some(pkt))
     }
 }
 
 pub struct SendHalf {
     pub(crate) pcb: usize,
+    pub(crate) netif_idx: u8,
 }
 
 impl SendHalf {
@@ -148,6 +270,24 @@ impl SendHalf {
     ) -> io::Result<()> {
         send_udp(src_addr, dst_addr, self.pcb, data)
     }
+
+    /// Sends an ICMP/ICMPv6 destination unreachable message back to the
+    /// sender of a datagram received as `(_, src_addr, dst_addr)`.
+    pub fn send_unreachable(
+        &self,
+        reason: Unreachable,
+        src_addr: &SocketAddr,
+        dst_addr: &SocketAddr,
+    ) -> io::Result<()> {
+        icmp::send_unreachable(
+            self.netif_idx,
+            reason,
+            IP_PROTO_UDP as u8,
+            src_addr,
+            dst_addr,
+            udp_unreachable_hdr(src_addr, dst_addr),
+        )
+    }
 }
 
 pub struct RecvHalf {
@@ -158,10 +298,7 @@ impl RecvHalf {
     pub async fn recv_from(&mut self) -> io::Result<UdpPkt> {
         match self.socket.next().await {
             Some(pkt) => Ok(pkt),
-            None => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("recv_from udp socket faied: tx closed"),
-            )),
+            None => Err(io::Error::other("recv_from udp socket faied: tx closed")),
         }
     }
 }
@@ -173,3 +310,179 @@ impl Stream for RecvHalf {
         Pin::new(&mut self.socket).poll_next(cx)
     }
 }
+
+type FlowKey = (SocketAddr, SocketAddr);
+// Flow id, to tell a closed flow from its successor, its datagram queue, and
+// when a datagram last went either way, on the stack clock.
+type FlowEntry = (u64, Sender<Vec<u8>>, Duration);
+
+struct FlowTable {
+    flows: Mutex<HashMap<FlowKey, FlowEntry>>,
+    next_id: AtomicU64,
+    // Set on the driver thread once the pcb is gone.
+    closed: AtomicBool,
+}
+
+impl FlowTable {
+    /// Closes the flows idle for `timeout`, on the driver thread.
+    fn expire(&self, timeout: Duration) {
+        let now = clock::now();
+        self.flows
+            .lock()
+            .unwrap()
+            .retain(|(local_addr, remote_addr), (_, _, last_active)| {
+                let idle = now.saturating_sub(*last_active) >= timeout;
+                if idle {
+                    trace!("netstack udp flow {} -> {} idle", local_addr, remote_addr);
+                }
+                !idle
+            });
+    }
+
+    fn is_open(&self, key: &FlowKey, id: u64) -> bool {
+        matches!(self.flows.lock().unwrap().get(key), Some((i, ..)) if *i == id)
+    }
+
+    fn touch(&self, key: &FlowKey, id: u64) {
+        if let Some((i, _, last_active)) = self.flows.lock().unwrap().get_mut(key) {
+            if *i == id {
+                *last_active = clock::now();
+            }
+        }
+    }
+}
+
+/// Yields a `UdpFlow` for each new (source, destination) pair seen on the
+/// stack, see `UdpSocket::listen`.
+pub struct UdpListener {
+    socket: Pin<Box<UdpSocket>>,
+    shared: Arc<FlowTable>,
+    ticker: u64,
+    flow_buffer_size: usize,
+    /// Only used by the receive callback, on the driver thread.
+    tx: Mutex<Sender<UdpFlow>>,
+    rx: Receiver<UdpFlow>,
+    _pin: PhantomPinned,
+}
+
+impl Drop for UdpListener {
+    fn drop(&mut self) {
+        let (pcb, ticker) = (self.socket.pcb, self.ticker);
+        let shared = &self.shared;
+        driver::call(|| unsafe {
+            udp_recv(pcb as *mut udp_pcb, None, std::ptr::null_mut());
+            driver::remove_ticker(ticker);
+            shared.closed.store(true, Ordering::Relaxed);
+        });
+        // Closes the receiving end of every flow.
+        self.shared.flows.lock().unwrap().clear();
+    }
+}
+
+impl Stream for UdpListener {
+    type Item = UdpFlow;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = unsafe { self.get_unchecked_mut() };
+        this.rx.poll_next_unpin(cx)
+    }
+}
+
+/// The datagrams exchanged between a TUN side endpoint and one destination.
+///
+/// The flow ends, and `recv` returns `None`, once it has been idle for the
+/// timeout given to `UdpSocket::listen`, or when the listener is dropped.
+/// A datagram arriving after that starts a new flow.
+pub struct UdpFlow {
+    id: u64,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    pcb: usize,
+    netif_idx: u8,
+    rx: Receiver<Vec<u8>>,
+    shared: Arc<FlowTable>,
+}
+
+impl UdpFlow {
+    /// The TUN side endpoint.
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.local_addr
+    }
+
+    /// The destination the TUN side endpoint is talking to.
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.next().await
+    }
+
+    /// Sends a datagram to the TUN side endpoint, from the remote address.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "udp flow closed",
+            ));
+        }
+        let (shared, pcb) = (&self.shared, self.pcb);
+        let (local_addr, remote_addr) = (&self.local_addr, &self.remote_addr);
+        driver::call(|| unsafe {
+            if shared.closed.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "udp listener has been closed",
+                ));
+            }
+            output_udp(remote_addr, local_addr, pcb, data)
+        })?;
+        self.shared
+            .touch(&(self.local_addr, self.remote_addr), self.id);
+        Ok(())
+    }
+
+    /// Sends an ICMP/ICMPv6 destination unreachable message to the TUN side
+    /// endpoint about this flow, and closes it.
+    pub fn send_unreachable(mut self, reason: Unreachable) -> io::Result<()> {
+        self.close();
+        icmp::send_unreachable(
+            self.netif_idx,
+            reason,
+            IP_PROTO_UDP as u8,
+            &self.local_addr,
+            &self.remote_addr,
+            udp_unreachable_hdr(&self.local_addr, &self.remote_addr),
+        )
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self
+            .shared
+            .is_open(&(self.local_addr, self.remote_addr), self.id)
+    }
+
+    fn close(&mut self) {
+        let mut flows = self.shared.flows.lock().unwrap();
+        let key = (self.local_addr, self.remote_addr);
+        if matches!(flows.get(&key), Some((id, ..)) if *id == self.id) {
+            flows.remove(&key);
+        }
+        drop(flows);
+        self.rx.close();
+    }
+}
+
+impl Drop for UdpFlow {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Stream for UdpFlow {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
@@ -1,4 +1,5 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 use super::lwip::*;
 
@@ -17,10 +18,10 @@ pub fn to_socket_addr(addr: &ip_addr_t, port: u16_t) -> SocketAddr {
                 let p2 = addr[2].to_ne_bytes();
                 let p3 = addr[3].to_ne_bytes();
                 let mut p = [0u8; 16];
-                (&mut p[0..4]).copy_from_slice(&p0);
-                (&mut p[4..8]).copy_from_slice(&p1);
-                (&mut p[8..12]).copy_from_slice(&p2);
-                (&mut p[12..16]).copy_from_slice(&p3);
+                p[0..4].copy_from_slice(&p0);
+                p[4..8].copy_from_slice(&p1);
+                p[8..12].copy_from_slice(&p2);
+                p[12..16].copy_from_slice(&p3);
                 let addr = Ipv6Addr::from(p);
                 SocketAddr::new(IpAddr::V6(addr), port)
             }
@@ -71,6 +72,46 @@ pub fn to_ip_addr_t(ip: IpAddr) -> ip_addr_t {
     }
 }
 
+/// Computes the Internet checksum (RFC 1071) over the concatenation of `data`.
+pub fn checksum(data: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+    for &b in data.iter().flat_map(|d| d.iter()) {
+        match odd.take() {
+            Some(hi) => sum += u16::from_be_bytes([hi, b]) as u32,
+            None => odd = Some(b),
+        }
+    }
+    if let Some(hi) = odd {
+        sum += (hi as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Checksum of an ICMP message, or of an ICMPv6 message including its
+/// pseudo header.
+pub fn icmp_checksum(src: &IpAddr, dst: &IpAddr, msg: &[u8]) -> u16 {
+    match (src, dst) {
+        (IpAddr::V6(src), IpAddr::V6(dst)) => checksum(&[
+            &src.octets(),
+            &dst.octets(),
+            &(msg.len() as u32).to_be_bytes(),
+            &[0, 0, 0, IP6_NEXTH_ICMP6 as u8],
+            msg,
+        ]),
+        _ => checksum(&[msg]),
+    }
+}
+
+/// Converts a timeout into ticks of lwIP's slow TCP timer, rounding up.
+pub fn slow_ticks(timeout: Duration) -> u32 {
+    let ticks = timeout.as_millis().div_ceil(TCP_SLOW_INTERVAL as u128);
+    ticks.clamp(1, i32::MAX as u128) as u32
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -87,6 +128,14 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_checksum() {
+        // Example from RFC 1071, section 3.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&[&data]), !0xddf2);
+        assert_eq!(checksum(&[&data[..3], &data[3..]]), !0xddf2);
+    }
+
     #[test]
     fn test_to_ip_addr_t() {
         let addr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
@@ -94,4 +143,12 @@ mod test {
         let addr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
         assert_eq!(to_ip_addr_t(addr).type_, 6);
     }
+
+    #[test]
+    fn test_slow_ticks() {
+        assert_eq!(slow_ticks(Duration::ZERO), 1);
+        assert_eq!(slow_ticks(Duration::from_millis(500)), 1);
+        assert_eq!(slow_ticks(Duration::from_millis(501)), 2);
+        assert_eq!(slow_ticks(Duration::MAX), i32::MAX as u32);
+    }
 }
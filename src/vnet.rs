@@ -0,0 +1,122 @@
+//! The `virtio_net_hdr` in front of the packets of a Linux TUN device opened
+//! with `IFF_VNET_HDR`, see `NetStackBuilder::virtio_net_hdr`.
+
+use bytes::{Buf, Bytes};
+
+use super::lwip::*;
+use super::pbuf::copy_from_pbuf_at;
+use super::util;
+
+/// Size of the header without `num_buffers`, the default of `IFF_VNET_HDR`.
+pub(crate) const VNET_HDR_LEN: usize = 10;
+
+const F_NEEDS_CSUM: u8 = 1;
+const F_DATA_VALID: u8 = 2;
+
+const GSO_NONE: u8 = 0;
+const GSO_TCPV4: u8 = 1;
+const GSO_TCPV6: u8 = 4;
+const GSO_ECN: u8 = 0x80;
+
+/// Offset of the checksum in the TCP header.
+const TCP_CSUM_OFFSET: usize = 16;
+
+/// Strips the header of a packet written into the sink. Returns whether the
+/// kernel vouches for its checksums, which are only partial when it left
+/// them to the device, or `None` if lwIP can't take the packet.
+pub(crate) fn strip(pkt: &mut Bytes) -> Option<bool> {
+    if pkt.len() < VNET_HDR_LEN {
+        return None;
+    }
+    let (flags, gso_type) = (pkt[0], pkt[1]);
+    // lwIP takes a TCP segment larger than the MSS as a whole, there is no
+    // such thing for UDP.
+    if !matches!(gso_type & !GSO_ECN, GSO_NONE | GSO_TCPV4 | GSO_TCPV6) {
+        return None;
+    }
+    pkt.advance(VNET_HDR_LEN);
+    Some(flags & (F_NEEDS_CSUM | F_DATA_VALID) != 0)
+}
+
+/// Copies a packet emitted by lwIP behind a header, on the driver thread.
+///
+/// The netif does not generate TCP checksums, lwIP left zero in their
+/// place. The sum of the pseudo header goes there instead and the kernel
+/// adds the one of the segment, as for `CHECKSUM_PARTIAL`.
+pub(crate) unsafe fn copy_from_pbuf(p: *mut pbuf) -> Vec<u8> {
+    let mut buf = copy_from_pbuf_at(p, VNET_HDR_LEN);
+    let (hdr, pkt) = buf.split_at_mut(VNET_HDR_LEN);
+    if let Some((start, sum)) = tcp_pseudo_sum(pkt) {
+        let csum = start + TCP_CSUM_OFFSET;
+        pkt[csum..csum + 2].copy_from_slice(&sum.to_be_bytes());
+        hdr[0] = F_NEEDS_CSUM;
+        hdr[6..8].copy_from_slice(&(start as u16).to_ne_bytes());
+        hdr[8..10].copy_from_slice(&(TCP_CSUM_OFFSET as u16).to_ne_bytes());
+    }
+    buf
+}
+
+/// Offset of the TCP header of an unfragmented packet, and the folded sum
+/// of its pseudo header.
+fn tcp_pseudo_sum(pkt: &[u8]) -> Option<(usize, u16)> {
+    let (start, addrs) = match pkt.first()? >> 4 {
+        4 if pkt.len() >= 20
+            && pkt[9] == IP_PROTO_TCP as u8
+            && u16::from_be_bytes([pkt[6], pkt[7]]) & 0x3fff == 0 =>
+        {
+            ((pkt[0] & 0x0f) as usize * 4, &pkt[12..20])
+        }
+        6 if pkt.len() >= 40 && pkt[6] == IP_PROTO_TCP as u8 => (40, &pkt[8..40]),
+        _ => return None,
+    };
+    let tcp_len = pkt.len().checked_sub(start)?;
+    if tcp_len < 20 {
+        return None;
+    }
+    let len = (tcp_len as u16).to_be_bytes();
+    let sum = !util::checksum(&[addrs, &[0, IP_PROTO_TCP as u8], &len]);
+    Some((start, sum))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        let pkt = |flags, gso_type| {
+            let mut pkt = vec![0; VNET_HDR_LEN];
+            pkt[0] = flags;
+            pkt[1] = gso_type;
+            pkt.extend_from_slice(&[0x45, 0]);
+            Bytes::from(pkt)
+        };
+        let mut p = pkt(0, GSO_NONE);
+        assert_eq!(strip(&mut p), Some(false));
+        assert_eq!(&p[..], &[0x45, 0]);
+        assert_eq!(strip(&mut pkt(F_NEEDS_CSUM, GSO_TCPV6)), Some(true));
+        assert_eq!(
+            strip(&mut pkt(F_DATA_VALID, GSO_TCPV4 | GSO_ECN)),
+            Some(true)
+        );
+        // UDP_L4
+        assert_eq!(strip(&mut pkt(0, 5)), None);
+        assert_eq!(strip(&mut Bytes::from_static(&[0; 4])), None);
+    }
+
+    #[test]
+    fn test_tcp_pseudo_sum() {
+        let mut pkt = vec![0; 40];
+        pkt[0] = 0x45;
+        pkt[9] = IP_PROTO_TCP as u8;
+        pkt[12..20].copy_from_slice(&[10, 0, 0, 1, 0xff, 0xff, 0xff, 0xff]);
+        // 0x0a00 + 0x0001 + 0xffff + 0xffff + 6 + 20, folded.
+        assert_eq!(tcp_pseudo_sum(&pkt), Some((20, 0x0a1b)));
+        // A fragment.
+        pkt[6] = 0x20;
+        assert_eq!(tcp_pseudo_sum(&pkt), None);
+        pkt[6] = 0;
+        pkt[9] = 17;
+        assert_eq!(tcp_pseudo_sum(&pkt), None);
+    }
+}
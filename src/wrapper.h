@@ -4,3 +4,6 @@
 #include "lwip/include/lwip/tcp.h"
 #include "lwip/include/lwip/udp.h"
 #include "lwip/include/lwip/ip_addr.h"
+#include "lwip/include/lwip/raw.h"
+#include "lwip/include/lwip/priv/tcp_priv.h"
+#include "lwip/custom/netstack_stats.h"
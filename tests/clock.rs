@@ -0,0 +1,207 @@
+//! Timeouts fast-forwarded with a `VirtualClock`.
+//!
+//! The clock is set for the whole process, the tests of this file take turns
+//! on it.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::executor::block_on;
+use futures::{AsyncWriteExt, SinkExt, StreamExt};
+use netstack_lwip::test_util::{Packet, Peer, TcpSegment, UdpDatagram, ACK, FIN, RST, SYN};
+use netstack_lwip::{Error, NetStack, SyncNetStack, TcpState, VirtualClock};
+
+fn clock() -> MutexGuard<'static, VirtualClock> {
+    static CLOCK: OnceLock<Mutex<VirtualClock>> = OnceLock::new();
+    CLOCK
+        .get_or_init(|| {
+            let clock = VirtualClock::new();
+            NetStack::set_clock(clock.clone());
+            Mutex::new(clock)
+        })
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+fn input(s: &mut SyncNetStack, seq: u32, ack: u32, flags: u8) {
+    let seg = TcpSegment {
+        src: addr("10.0.0.1:1000"),
+        dst: addr("1.1.1.1:80"),
+        seq,
+        ack,
+        flags,
+        window: 65535,
+        options: Vec::new(),
+        payload: Bytes::new(),
+    };
+    s.input(&seg.to_packet()).unwrap();
+}
+
+fn output(s: &mut SyncNetStack) -> Option<TcpSegment> {
+    match Packet::parse(s.poll_output()?) {
+        Packet::Tcp(seg) => Some(seg),
+        p => panic!("unexpected {:?}", p),
+    }
+}
+
+#[test]
+fn tcp_timers() {
+    let clock = clock();
+    let mut s = NetStack::builder().build_sync().unwrap();
+    input(&mut s, 100, 0, SYN);
+    let iss = output(&mut s).unwrap().seq;
+    input(&mut s, 101, iss + 1, ACK);
+    let mut h = s.accept().unwrap();
+    h.write(b"data").unwrap();
+    let seg = output(&mut s).unwrap();
+    assert_eq!((seg.seq, &seg.payload[..]), (iss + 1, &b"data"[..]));
+
+    // Nothing is retransmitted in real time, only as the clock moves.
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(s.poll_output().is_none());
+    s.advance_time(Duration::from_secs(10));
+    let mut retransmissions = 0;
+    while let Some(seg) = output(&mut s) {
+        assert_eq!((seg.seq, &seg.payload[..]), (iss + 1, &b"data"[..]));
+        retransmissions += 1;
+    }
+    assert!(retransmissions >= 2);
+
+    input(&mut s, 101, iss + 5, ACK);
+    h.stream()
+        .set_keepalive(Duration::from_secs(60), Duration::from_secs(10), 3)
+        .unwrap();
+    clock.advance(Duration::from_secs(59));
+    assert!(s.poll_output().is_none());
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(output(&mut s).unwrap().seq, iss + 4);
+
+    // Closed first on the stack side, the connection lingers in TIME_WAIT.
+    h.shutdown().unwrap();
+    assert!(output(&mut s).unwrap().has(FIN));
+    input(&mut s, 101, iss + 6, FIN | ACK);
+    drop(h);
+    while s.poll_output().is_some() {}
+    let states: Vec<_> = s.stack().connections().iter().map(|c| c.state).collect();
+    assert_eq!(states, vec![TcpState::TimeWait]);
+    clock.advance(Duration::from_secs(121));
+    assert!(s.stack().connections().is_empty());
+}
+
+#[test]
+fn udp_flow_idle() {
+    let clock = clock();
+    let (stack, _tcp, udp) = NetStack::new().unwrap();
+    let mut listener = udp.listen(Duration::from_secs(30));
+    let (mut sink, _stream) = stack.split();
+    let dgram = UdpDatagram {
+        src: addr("10.0.0.1:1000"),
+        dst: addr("1.1.1.1:53"),
+        payload: Bytes::from_static(b"query"),
+    };
+    block_on(sink.send(dgram.to_packet())).unwrap();
+    let mut flow = block_on(listener.next()).unwrap();
+    assert_eq!(block_on(flow.recv()).unwrap(), b"query");
+
+    clock.advance(Duration::from_secs(20));
+    assert!(!flow.is_closed());
+    flow.send(b"answer").unwrap();
+    clock.advance(Duration::from_secs(20));
+    assert!(!flow.is_closed());
+    clock.advance(Duration::from_secs(15));
+    assert!(flow.is_closed());
+    assert!(block_on(flow.recv()).is_none());
+}
+
+#[test]
+fn tcp_connect_timeout() {
+    let clock = clock();
+    let (stack, _tcp, _udp) = NetStack::new().unwrap();
+    let connect = stack.connect(addr("1.1.1.1:4000"), addr("10.0.0.1:22"));
+    // Nobody answers the SYN.
+    let (res, ()) = block_on(futures::future::join(connect, async {
+        clock.advance(Duration::from_secs(600))
+    }));
+    let err = res.err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(err.get_ref().unwrap().downcast_ref(), Some(&Error::Timeout));
+}
+
+#[test]
+fn tcp_idle_timeout() {
+    let clock = clock();
+    let mut s = NetStack::builder()
+        .idle_timeout(Duration::from_secs(60))
+        .build_sync()
+        .unwrap();
+    input(&mut s, 100, 0, SYN);
+    let iss = output(&mut s).unwrap().seq;
+    input(&mut s, 101, iss + 1, ACK);
+    let mut h = s.accept().unwrap();
+
+    clock.advance(Duration::from_secs(50));
+    assert!(s.poll_output().is_none());
+    // Activity from the peer pushes the deadline back.
+    input(&mut s, 101, iss + 1, ACK);
+    clock.advance(Duration::from_secs(50));
+    assert!(s.poll_output().is_none());
+    clock.advance(Duration::from_secs(15));
+    assert!(output(&mut s).unwrap().has(RST));
+    let mut buf = [0u8; 4];
+    let err = h.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(
+        err.get_ref().unwrap().downcast_ref(),
+        Some(&Error::IdleTimeout)
+    );
+    assert_eq!(s.stack().stats().idle_timeouts, 1);
+}
+
+#[test]
+fn tcp_pcb_eviction() {
+    let clock = clock();
+    let (stack, mut listener, _udp) = NetStack::builder().pcb_eviction(true).build().unwrap();
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    listener.as_mut().set_eviction_callback({
+        let evicted = evicted.clone();
+        move |e| evicted.lock().unwrap().push(e)
+    });
+    let (sink, stream) = stack.split();
+    let mut peer = Peer::new(sink, stream);
+    let remote = addr("1.1.1.1:80");
+    let mut streams = Vec::new();
+
+    // The connection idle the longest.
+    block_on(peer.connect(addr("10.0.0.1:1000"), remote)).unwrap();
+    streams.push(block_on(listener.next()).unwrap().0);
+    clock.advance(Duration::from_secs(5));
+    // A connection in TIME_WAIT, closed by the stack first.
+    let mut conn = block_on(peer.connect(addr("10.0.0.1:1001"), remote)).unwrap();
+    let mut s = block_on(listener.next()).unwrap().0;
+    block_on(s.close()).unwrap();
+    block_on(peer.read_to_end(&mut conn)).unwrap();
+    block_on(peer.shutdown(&mut conn)).unwrap();
+    clock.advance(Duration::from_secs(5));
+
+    // Fill the pcb pool up, until new connections evict older ones.
+    for port in 2000.. {
+        let local = SocketAddr::new(addr("10.0.0.2:0").ip(), port);
+        block_on(peer.connect(local, remote)).unwrap();
+        streams.push(block_on(listener.next()).unwrap().0);
+        if evicted.lock().unwrap().len() == 2 {
+            break;
+        }
+    }
+    let evicted = evicted.lock().unwrap();
+    assert_eq!(evicted[0].local_addr, addr("10.0.0.1:1001"));
+    assert_eq!(evicted[0].state, TcpState::TimeWait);
+    assert_eq!(evicted[1].local_addr, addr("10.0.0.1:1000"));
+    assert_eq!(evicted[1].state, TcpState::Established);
+    assert!(evicted[1].idle >= Duration::from_secs(10));
+}
@@ -0,0 +1,650 @@
+//! End to end exchanges between a simulated TUN side host and the stack.
+
+#![cfg(feature = "tokio")]
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use netstack_lwip::test_util::{Packet, Peer, TcpConn, TcpSegment, UdpDatagram, ACK, RST, SYN};
+use netstack_lwip::{Capture, NetStack, OverflowPolicy, SynRequest, Unreachable};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+fn run(f: impl std::future::Future<Output = ()>) {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            tokio::time::timeout(Duration::from_secs(10), f)
+                .await
+                .unwrap()
+        });
+}
+
+#[test]
+fn tcp_echo() {
+    for (local, remote) in [
+        ("10.0.0.1:1000", "1.1.1.1:80"),
+        ("[fd00::1]:1000", "[2001:db8::1]:80"),
+    ] {
+        run(async {
+            let (stack, mut listener, _udp) = NetStack::new().unwrap();
+            let (sink, stream) = stack.split();
+            let mut peer = Peer::new(sink, stream);
+            let server = tokio::spawn(async move {
+                let (mut s, local_addr, remote_addr) = listener.next().await.unwrap();
+                assert_eq!(local_addr, addr(local));
+                assert_eq!(remote_addr, addr(remote));
+                let mut data = Vec::new();
+                s.read_to_end(&mut data).await.unwrap();
+                s.write_all(&data).await.unwrap();
+                s.shutdown().await.unwrap();
+                s
+            });
+            let mut conn = peer.connect(addr(local), addr(remote)).await.unwrap();
+            let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+            peer.write(&mut conn, &data).await.unwrap();
+            peer.shutdown(&mut conn).await.unwrap();
+            assert_eq!(peer.read_to_end(&mut conn).await.unwrap(), data);
+            drop(server.await.unwrap());
+        });
+    }
+}
+
+#[test]
+fn tcp_reset() {
+    run(async {
+        let (stack, mut listener, _udp) = NetStack::new().unwrap();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let mut conn = peer
+            .connect(addr("10.0.0.1:1000"), addr("1.1.1.1:80"))
+            .await
+            .unwrap();
+        let (s, _, _) = listener.next().await.unwrap();
+        // Dropped without being shut down.
+        drop(s);
+        let err = peer.read(&mut conn).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+        let conn = peer
+            .connect(addr("10.0.0.1:1001"), addr("1.1.1.1:80"))
+            .await
+            .unwrap();
+        let (mut s, _, _) = listener.next().await.unwrap();
+        peer.reset(&conn).await.unwrap();
+        let mut buf = [0u8; 8];
+        let err = s.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    });
+}
+
+#[test]
+fn multiple_stacks() {
+    run(async {
+        let (a, mut a_listener, _a_udp) = NetStack::new().unwrap();
+        let (b, mut b_listener, b_udp) = NetStack::new().unwrap();
+        let (sink, stream) = a.split();
+        let mut a_peer = Peer::new(sink, stream);
+        let (sink, stream) = b.split();
+        let mut b_peer = Peer::new(sink, stream);
+        // The same addresses on both stacks.
+        let (local, remote) = (addr("10.0.0.1:1000"), addr("1.1.1.1:80"));
+        let mut a_conn = a_peer.connect(local, remote).await.unwrap();
+        let (mut a_s, _, _) = a_listener.next().await.unwrap();
+        let mut b_conn = b_peer.connect(local, remote).await.unwrap();
+        let (mut b_s, _, _) = b_listener.next().await.unwrap();
+
+        b_peer.write(&mut b_conn, b"to b").await.unwrap();
+        a_peer.write(&mut a_conn, b"to a").await.unwrap();
+        let mut buf = [0u8; 4];
+        a_s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"to a");
+        b_s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"to b");
+        a_s.write_all(b"from a").await.unwrap();
+        assert_eq!(&a_peer.read(&mut a_conn).await.unwrap()[..], b"from a");
+
+        // Nothing leaks into the other stack.
+        let (_send, mut b_recv) = b_udp.split();
+        a_peer
+            .send_udp(local, addr("1.1.1.1:53"), b"query")
+            .await
+            .unwrap();
+        let quiet = Duration::from_millis(200);
+        assert!(tokio::time::timeout(quiet, b_recv.recv_from())
+            .await
+            .is_err());
+        let data = |p: &Packet| !matches!(p, Packet::Tcp(seg) if seg.payload.is_empty());
+        assert!(tokio::time::timeout(quiet, b_peer.recv_matching(data))
+            .await
+            .is_err());
+    });
+}
+
+#[test]
+fn tcp_connect() {
+    run(async {
+        let (stack, _listener, _udp) = NetStack::new().unwrap();
+        let (local, remote) = (addr("1.1.1.1:4000"), addr("10.0.0.1:22"));
+        let connect = tokio::spawn(stack.connect(local, remote));
+        let refused = tokio::spawn(stack.connect(addr("1.1.1.1:4001"), remote));
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let mut rcv_nxt = 0;
+        for _ in 0..2 {
+            let is_syn = |p: &Packet| matches!(p, Packet::Tcp(seg) if seg.flags == SYN);
+            let syn = match peer.recv_matching(is_syn).await.unwrap() {
+                Packet::Tcp(seg) => seg,
+                pkt => panic!("unexpected {:?}", pkt),
+            };
+            let flags = if syn.src == local {
+                rcv_nxt = syn.seq.wrapping_add(1);
+                SYN | ACK
+            } else {
+                RST | ACK
+            };
+            let reply = TcpSegment {
+                src: syn.dst,
+                dst: syn.src,
+                seq: 5000,
+                ack: syn.seq.wrapping_add(1),
+                flags,
+                window: u16::MAX,
+                options: Vec::new(),
+                payload: Default::default(),
+            };
+            peer.send(reply.to_packet()).await.unwrap();
+        }
+        let err = refused.await.unwrap().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        let mut s = connect.await.unwrap().unwrap();
+        // The TUN side is local, as for accepted streams.
+        assert_eq!((*s.local_addr(), *s.remote_addr()), (remote, local));
+        s.write_all(b"hello").await.unwrap();
+        let mut conn = TcpConn {
+            local: remote,
+            remote: local,
+            snd_nxt: 5001,
+            rcv_nxt,
+            fin_received: false,
+        };
+        assert_eq!(&peer.read(&mut conn).await.unwrap()[..], b"hello");
+        peer.write(&mut conn, b"world").await.unwrap();
+        let mut buf = [0u8; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    });
+}
+
+#[test]
+fn syn_filter() {
+    run(async {
+        let (stack, mut listener, _udp) = NetStack::new().unwrap();
+        let (held_tx, mut held_rx) = futures::channel::mpsc::unbounded();
+        listener
+            .as_mut()
+            .set_syn_filter(move |req: SynRequest| match req.remote_addr().port() {
+                81 => req.reset(),
+                82 => req.unreachable(Unreachable::Port),
+                83 => held_tx.unbounded_send(req).unwrap(),
+                _ => req.accept(),
+            });
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let local = addr("10.0.0.1:1000");
+
+        peer.connect(local, addr("1.1.1.1:80")).await.unwrap();
+        let (_s, _, remote_addr) = listener.next().await.unwrap();
+        assert_eq!(remote_addr, addr("1.1.1.1:80"));
+
+        let err = peer.connect(local, addr("1.1.1.1:81")).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        let syn = |port| TcpSegment {
+            src: local,
+            dst: SocketAddr::new(addr("1.1.1.1:0").ip(), port),
+            seq: 1000,
+            ack: 0,
+            flags: SYN,
+            window: u16::MAX,
+            options: Vec::new(),
+            payload: Default::default(),
+        };
+        peer.send(syn(82).to_packet()).await.unwrap();
+        match peer.recv().await.unwrap() {
+            // Port unreachable.
+            Packet::Other(pkt) => assert_eq!((pkt[9], pkt[20], pkt[21]), (1, 3, 3)),
+            pkt => panic!("unexpected {:?}", pkt),
+        }
+
+        // Retransmissions of a held SYN are dropped, without calling the
+        // filter again.
+        peer.send(syn(83).to_packet()).await.unwrap();
+        peer.send(syn(83).to_packet()).await.unwrap();
+        let req = held_rx.next().await.unwrap();
+        let quiet = Duration::from_millis(200);
+        assert!(tokio::time::timeout(quiet, peer.recv()).await.is_err());
+        assert!(held_rx.try_recv().is_err());
+        req.accept();
+        match peer.recv().await.unwrap() {
+            Packet::Tcp(seg) => {
+                assert!(seg.has(SYN | ACK));
+                assert_eq!((seg.src.port(), seg.ack), (83, 1001));
+            }
+            pkt => panic!("unexpected {:?}", pkt),
+        }
+    });
+}
+
+#[test]
+fn syn_filter_held_options() {
+    run(async {
+        let (stack, mut listener, _udp) = NetStack::builder()
+            .window_scaling(false)
+            .checksum_validation(true)
+            .build()
+            .unwrap();
+        let (held_tx, mut held_rx) = futures::channel::mpsc::unbounded();
+        listener
+            .as_mut()
+            .set_syn_filter(move |req: SynRequest| held_tx.unbounded_send(req).unwrap());
+        let stats = stack.stats_handle();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let syn = TcpSegment {
+            src: addr("10.0.0.1:1000"),
+            dst: addr("1.1.1.1:80"),
+            seq: 1000,
+            ack: 0,
+            flags: SYN,
+            window: u16::MAX,
+            // NOP, window scale 7.
+            options: vec![1, 3, 3, 7],
+            payload: Default::default(),
+        };
+        peer.send(syn.to_packet()).await.unwrap();
+        // Fed back to lwIP once accepted, with the options it arrived with.
+        held_rx.next().await.unwrap().accept();
+        match peer.recv().await.unwrap() {
+            Packet::Tcp(seg) => {
+                assert!(seg.has(SYN | ACK));
+                assert!(!seg.options.windows(2).any(|o| o == [3, 3]));
+            }
+            pkt => panic!("unexpected {:?}", pkt),
+        }
+        assert_eq!(stats.stats().checksum_errors, 0);
+    });
+}
+
+#[test]
+fn tcp_backlog() {
+    run(async {
+        let (stack, _listener, _udp) = NetStack::builder().backlog(2).build().unwrap();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let syn = |port| TcpSegment {
+            src: SocketAddr::new(addr("10.0.0.1:0").ip(), port),
+            dst: addr("1.1.1.1:80"),
+            seq: 1000,
+            ack: 0,
+            flags: SYN,
+            window: u16::MAX,
+            options: Vec::new(),
+            payload: Default::default(),
+        };
+        for port in 1000..1003 {
+            peer.send(syn(port).to_packet()).await.unwrap();
+        }
+        let mut synacks = Vec::new();
+        let quiet = Duration::from_millis(200);
+        while let Ok(pkt) = tokio::time::timeout(quiet, peer.recv()).await {
+            match pkt.unwrap() {
+                Packet::Tcp(seg) if seg.has(SYN | ACK) => synacks.push(seg),
+                pkt => panic!("unexpected {:?}", pkt),
+            }
+        }
+        // The third SYN went past the backlog.
+        let ports: Vec<_> = synacks.iter().map(|seg| seg.dst.port()).collect();
+        assert_eq!(ports, [1000, 1001]);
+
+        // Completing a handshake makes room.
+        let ack = TcpSegment {
+            seq: 1001,
+            ack: synacks[0].seq.wrapping_add(1),
+            flags: ACK,
+            ..syn(1000)
+        };
+        peer.send(ack.to_packet()).await.unwrap();
+        peer.send(syn(1002).to_packet()).await.unwrap();
+        match peer.recv().await.unwrap() {
+            Packet::Tcp(seg) => assert!(seg.has(SYN | ACK) && seg.dst.port() == 1002),
+            pkt => panic!("unexpected {:?}", pkt),
+        }
+    });
+}
+
+#[test]
+fn tcp_stack_dropped() {
+    run(async {
+        let (stack, mut listener, _udp) = NetStack::new().unwrap();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        peer.connect(addr("10.0.0.1:1000"), addr("1.1.1.1:80"))
+            .await
+            .unwrap();
+        let (mut s, _, _) = listener.next().await.unwrap();
+        drop(peer);
+        let mut buf = [0u8; 8];
+        assert!(s.read(&mut buf).await.is_err());
+        assert!(s.write_all(&buf).await.is_err());
+    });
+}
+
+#[test]
+fn tcp_small_mss() {
+    run(async {
+        let (stack, mut listener, _udp) = NetStack::new().unwrap();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let (local, remote) = (addr("10.0.0.1:1000"), addr("1.1.1.1:80"));
+        // More segments than lwIP queues per connection for a single write.
+        let data = vec![0x61; 20_000];
+        let server = tokio::spawn({
+            let data = data.clone();
+            async move {
+                let (mut s, _, _) = listener.next().await.unwrap();
+                s.write_all(&data).await.unwrap();
+                s.shutdown().await.unwrap();
+                s
+            }
+        });
+        let syn = TcpSegment {
+            src: local,
+            dst: remote,
+            seq: 1000,
+            ack: 0,
+            flags: SYN,
+            window: u16::MAX,
+            options: vec![2, 4, 0, 16],
+            payload: Default::default(),
+        };
+        peer.send(syn.to_packet()).await.unwrap();
+        let synack = match peer.recv().await.unwrap() {
+            Packet::Tcp(seg) if seg.has(SYN | ACK) => seg,
+            pkt => panic!("unexpected {:?}", pkt),
+        };
+        let mut conn = TcpConn {
+            local,
+            remote,
+            snd_nxt: 1001,
+            rcv_nxt: synack.seq.wrapping_add(1),
+            fin_received: false,
+        };
+        let ack = TcpSegment {
+            seq: conn.snd_nxt,
+            ack: conn.rcv_nxt,
+            flags: ACK,
+            options: Vec::new(),
+            ..syn
+        };
+        peer.send(ack.to_packet()).await.unwrap();
+        assert_eq!(peer.read_to_end(&mut conn).await.unwrap(), data);
+        drop(server.await.unwrap());
+    });
+}
+
+#[test]
+fn output_backpressure() {
+    run(async {
+        let (stack, mut listener, _udp) = NetStack::builder().buffer_size(4).build().unwrap();
+        stack.set_output_policy(OverflowPolicy::Backpressure);
+        let drops = stack.output_drops();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let mut conn = peer
+            .connect(addr("10.0.0.1:1000"), addr("1.1.1.1:80"))
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..50_000u32).map(|i| i as u8).collect();
+        let server = tokio::spawn({
+            let data = data.clone();
+            async move {
+                let (mut s, _, _) = listener.next().await.unwrap();
+                s.write_all(&data).await.unwrap();
+                s.shutdown().await.unwrap();
+                s
+            }
+        });
+        // Segments refused while the queue was full are sent again by lwIP.
+        assert_eq!(peer.read_to_end(&mut conn).await.unwrap(), data);
+        assert_eq!(drops.get(), 0);
+        drop(server.await.unwrap());
+    });
+}
+
+#[test]
+fn udp_exchange() {
+    run(async {
+        let (stack, _listener, udp) = NetStack::new().unwrap();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let (send, mut recv) = udp.split();
+        let (src, dst) = (addr("[fd00::1]:5353"), addr("[2001:db8::53]:53"));
+        peer.send_udp(src, dst, b"query").await.unwrap();
+        let (data, from, to) = recv.recv_from().await.unwrap();
+        assert_eq!((&data[..], from, to), (&b"query"[..], src, dst));
+        send.send_to(b"answer", &dst, &src).unwrap();
+        let datagram = peer.recv_udp().await.unwrap();
+        assert_eq!(
+            (&datagram.payload[..], datagram.src, datagram.dst),
+            (&b"answer"[..], dst, src)
+        );
+    });
+}
+
+#[test]
+fn udp_flows() {
+    run(async {
+        let (stack, _listener, udp) = NetStack::new().unwrap();
+        let mut flows = udp.listen(Duration::from_millis(300));
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let (a, b, dst) = (
+            addr("10.0.0.1:1000"),
+            addr("10.0.0.1:1001"),
+            addr("1.1.1.1:53"),
+        );
+        peer.send_udp(a, dst, b"a1").await.unwrap();
+        peer.send_udp(b, dst, b"b1").await.unwrap();
+        peer.send_udp(a, dst, b"a2").await.unwrap();
+        let mut fa = flows.next().await.unwrap();
+        let mut fb = flows.next().await.unwrap();
+        assert_eq!((*fa.local_addr(), *fa.remote_addr()), (a, dst));
+        assert_eq!((*fb.local_addr(), *fb.remote_addr()), (b, dst));
+        assert_eq!(fa.recv().await.unwrap(), b"a1");
+        assert_eq!(fa.recv().await.unwrap(), b"a2");
+        assert_eq!(fb.recv().await.unwrap(), b"b1");
+        fa.send(b"reply").unwrap();
+        let datagram = peer.recv_udp().await.unwrap();
+        assert_eq!(
+            (&datagram.payload[..], datagram.src, datagram.dst),
+            (&b"reply"[..], dst, a)
+        );
+
+        // An idle flow closes, the next datagram opens a new one.
+        assert!(fb.recv().await.is_none());
+        assert!(fb.is_closed());
+        peer.send_udp(b, dst, b"b2").await.unwrap();
+        let mut fb2 = flows.next().await.unwrap();
+        assert_eq!(fb2.recv().await.unwrap(), b"b2");
+        drop(fb);
+        peer.send_udp(b, dst, b"b3").await.unwrap();
+        assert_eq!(fb2.recv().await.unwrap(), b"b3");
+
+        drop(flows);
+        assert!(fa.recv().await.is_none());
+        assert!(fa.send(b"late").is_err());
+    });
+}
+
+#[test]
+fn checksum_validation() {
+    run(async {
+        let (stack, _listener, udp) = NetStack::builder()
+            .checksum_validation(true)
+            .build()
+            .unwrap();
+        let stats = stack.stats_handle();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let (_send, mut recv) = udp.split();
+        let corrupt = |pkt: bytes::Bytes, offset: usize| {
+            let mut pkt = pkt.to_vec();
+            pkt[offset] ^= 0xff;
+            pkt.into()
+        };
+
+        let (src, dst) = (addr("10.0.0.1:5353"), addr("1.1.1.1:53"));
+        let datagram = |payload: &'static [u8]| UdpDatagram {
+            src,
+            dst,
+            payload: payload.into(),
+        };
+        // The last payload byte, then the IPv4 header checksum.
+        let bad = datagram(b"bad").to_packet();
+        let last = bad.len() - 1;
+        peer.send(corrupt(bad, last)).await.unwrap();
+        peer.send(corrupt(datagram(b"bad").to_packet(), 10))
+            .await
+            .unwrap();
+        peer.send_udp(src, dst, b"good").await.unwrap();
+        let (data, _, _) = recv.recv_from().await.unwrap();
+        assert_eq!(&data[..], b"good");
+
+        let (local, remote) = (addr("[fd00::1]:1000"), addr("[2001:db8::1]:80"));
+        let syn = TcpSegment {
+            src: local,
+            dst: remote,
+            seq: 1000,
+            ack: 0,
+            flags: SYN,
+            window: u16::MAX,
+            options: Vec::new(),
+            payload: Default::default(),
+        };
+        // The TCP checksum, past the IPv6 header.
+        peer.send(corrupt(syn.to_packet(), 40 + 16)).await.unwrap();
+        peer.connect(local, remote).await.unwrap();
+        assert_eq!(stats.stats().checksum_errors, 3);
+    });
+}
+
+#[test]
+fn virtio_net_hdr() {
+    run(async {
+        let (stack, _listener, _udp) = NetStack::builder()
+            .checksum_validation(true)
+            .virtio_net_hdr(true)
+            .build()
+            .unwrap();
+        let stats = stack.stats_handle();
+        let (mut sink, mut stream) = stack.split();
+        let syn = TcpSegment {
+            src: addr("[fd00::1]:1000"),
+            dst: addr("[2001:db8::1]:80"),
+            seq: 1000,
+            ack: 0,
+            flags: SYN,
+            window: u16::MAX,
+            options: Vec::new(),
+            payload: Default::default(),
+        };
+        // Left partial by the kernel, i.e. wrong as far as lwIP can tell.
+        let mut pkt = syn.to_packet().to_vec();
+        pkt[40 + 16] ^= 0xff;
+        let mut hdr = vec![1, 0, 0, 0, 0, 0];
+        hdr.extend_from_slice(&40u16.to_ne_bytes());
+        hdr.extend_from_slice(&16u16.to_ne_bytes());
+        sink.send([hdr, pkt].concat().into()).await.unwrap();
+
+        let out = stream.next().await.unwrap().unwrap();
+        let (hdr, pkt) = out.split_at(10);
+        assert_eq!(hdr[0], 1);
+        assert_eq!(u16::from_ne_bytes([hdr[6], hdr[7]]), 40);
+        assert_eq!(u16::from_ne_bytes([hdr[8], hdr[9]]), 16);
+        // What the kernel does with the partial checksum.
+        let mut pkt = pkt.to_vec();
+        let mut sum = pkt[40..]
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+            .sum::<u32>();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        pkt[40 + 16..40 + 18].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        let synack = match Packet::parse(pkt.clone().into()) {
+            Packet::Tcp(seg) if seg.has(SYN | ACK) => seg,
+            pkt => panic!("unexpected {:?}", pkt),
+        };
+        assert_eq!(&synack.to_packet()[40..], &pkt[40..]);
+        let counters = stats.stats();
+        assert_eq!(counters.checksum_errors, 0);
+        // IP packets only, without the header.
+        assert_eq!(counters.bytes_in, syn.to_packet().len() as u64);
+        assert_eq!(counters.bytes_out, pkt.len() as u64);
+
+        // UDP segmentation offload is refused, and reported once.
+        let hdr = vec![0, 5, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(sink.send(hdr.into()).await.is_err());
+        assert_eq!(stats.stats().input_errors, 1);
+        sink.flush().await.unwrap();
+    });
+}
+
+#[test]
+fn capture_output_drops() {
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    run(async {
+        let (stack, _listener, udp) = NetStack::builder().buffer_size(1).build().unwrap();
+        stack.set_output_policy(OverflowPolicy::DropNewest);
+        let file = Shared::default();
+        stack.set_capture(Some(Capture::pcap(file.clone()).unwrap()));
+        let (send, _recv) = udp.split();
+        let (src, dst) = (addr("1.1.1.1:53"), addr("10.0.0.1:1000"));
+        for _ in 0..3 {
+            send.send_to(b"answer", &src, &dst).unwrap();
+        }
+        assert_eq!(stack.output_drops().get(), 2);
+
+        // The file header, then a record per packet emitted.
+        let pcap = file.0.lock().unwrap();
+        let mut at = 24;
+        let mut records = 0;
+        while at < pcap.len() {
+            let len = u32::from_le_bytes(pcap[at + 8..at + 12].try_into().unwrap());
+            assert_eq!(len, 20 + 8 + 6);
+            at += 16 + len as usize;
+            records += 1;
+        }
+        assert_eq!(records, 3);
+    });
+}
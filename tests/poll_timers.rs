@@ -0,0 +1,48 @@
+//! Timers run by the caller, without the `tokio` feature.
+
+#![cfg(not(feature = "tokio"))]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::executor::block_on;
+use futures::{AsyncReadExt, FutureExt, StreamExt};
+use netstack_lwip::test_util::{Packet, Peer, ACK};
+use netstack_lwip::NetStack;
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn tcp_delayed_ack() {
+    block_on(async {
+        let (stack, mut listener, _udp) = NetStack::new().unwrap();
+        let (sink, stream) = stack.split();
+        let mut peer = Peer::new(sink, stream);
+        let mut conn = peer
+            .connect(addr("10.0.0.1:1000"), addr("1.1.1.1:80"))
+            .await
+            .unwrap();
+        let (mut s, _, _) = listener.next().await.unwrap();
+        assert!(NetStack::poll_timers() <= Duration::from_millis(250));
+
+        // The ACK of a lone segment waits for the fast timer, which nothing
+        // runs on its own.
+        peer.write(&mut conn, b"data").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(peer.recv().now_or_never().is_none());
+
+        NetStack::poll_timers();
+        match peer.recv().now_or_never() {
+            Some(Ok(Packet::Tcp(seg))) => {
+                assert_eq!(seg.flags, ACK);
+                assert_eq!(seg.ack, conn.snd_nxt);
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+    });
+}
@@ -0,0 +1,92 @@
+//! Exchanges with a `SyncNetStack`, whose calls are done once they return.
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use netstack_lwip::test_util::{Packet, TcpSegment, UdpDatagram, ACK, FIN, PSH, SYN};
+use netstack_lwip::{NetStack, SyncNetStack};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+fn input(s: &mut SyncNetStack, seq: u32, ack: u32, flags: u8, payload: &'static [u8]) {
+    let seg = TcpSegment {
+        src: addr("10.0.0.1:1000"),
+        dst: addr("1.1.1.1:80"),
+        seq,
+        ack,
+        flags,
+        window: 65535,
+        options: Vec::new(),
+        payload: Bytes::from_static(payload),
+    };
+    s.input(&seg.to_packet()).unwrap();
+}
+
+fn output(s: &mut SyncNetStack) -> TcpSegment {
+    match Packet::parse(s.poll_output().unwrap()) {
+        Packet::Tcp(seg) => seg,
+        p => panic!("unexpected {:?}", p),
+    }
+}
+
+#[test]
+fn tcp_round_trip() {
+    let mut s = NetStack::builder().build_sync().unwrap();
+    assert!(s.poll_output().is_none());
+    assert!(s.accept().is_none());
+
+    input(&mut s, 100, 0, SYN, b"");
+    let synack = output(&mut s);
+    assert!(synack.has(SYN | ACK));
+    assert_eq!(synack.ack, 101);
+    let iss = synack.seq;
+    input(&mut s, 101, iss + 1, ACK, b"");
+    let mut h = s.accept().unwrap();
+    assert_eq!(*h.local_addr(), addr("10.0.0.1:1000"));
+    assert_eq!(*h.remote_addr(), addr("1.1.1.1:80"));
+
+    let mut buf = [0u8; 16];
+    assert_eq!(h.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+    input(&mut s, 101, iss + 1, PSH | ACK, b"hello");
+    assert_eq!(h.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    assert_eq!(h.write(b"world").unwrap(), 5);
+    let seg = output(&mut s);
+    assert_eq!((seg.seq, seg.ack), (iss + 1, 106));
+    assert_eq!(&seg.payload[..], b"world");
+
+    h.shutdown().unwrap();
+    let fin = output(&mut s);
+    assert!(fin.has(FIN));
+    assert_eq!(fin.seq, iss + 6);
+    input(&mut s, 106, iss + 7, FIN | ACK, b"");
+    assert_eq!(h.read(&mut buf).unwrap(), 0);
+    assert_eq!(output(&mut s).ack, 107);
+}
+
+#[test]
+fn udp_round_trip() {
+    let mut s = NetStack::builder().build_sync().unwrap();
+    let (local, remote) = (addr("10.0.0.1:1000"), addr("1.1.1.1:53"));
+    let query = UdpDatagram {
+        src: local,
+        dst: remote,
+        payload: Bytes::from_static(b"query"),
+    };
+    s.input(&query.to_packet()).unwrap();
+    assert_eq!(s.recv_udp(), Some((b"query".to_vec(), local, remote)));
+    assert_eq!(s.recv_udp(), None);
+
+    s.send_udp(b"answer", &remote, &local).unwrap();
+    match Packet::parse(s.poll_output().unwrap()) {
+        Packet::Udp(dgram) => {
+            assert_eq!((dgram.src, dgram.dst), (remote, local));
+            assert_eq!(&dgram.payload[..], b"answer");
+        }
+        p => panic!("unexpected {:?}", p),
+    }
+}
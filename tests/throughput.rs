@@ -0,0 +1,219 @@
+//! Bulk transfers from a simulated TUN side host toward the stack, over a
+//! link with a long round-trip time.
+
+#![cfg(feature = "tokio")]
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+
+const RTT: Duration = Duration::from_millis(100);
+const MSS: usize = 1460;
+const PEER_ISS: u32 = 1000;
+
+fn tcp_pkt(seq: u32, ack: u32, flags: u8, opts: &[u8], payload: &[u8]) -> Bytes {
+    let hdr_len = 20 + opts.len();
+    let tot_len = 20 + hdr_len + payload.len();
+    let mut p = vec![0u8; 20 + hdr_len];
+    p[0] = 0x45;
+    p[2..4].copy_from_slice(&(tot_len as u16).to_be_bytes());
+    p[8] = 64;
+    p[9] = 6;
+    p[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    p[16..20].copy_from_slice(&[1, 1, 1, 1]);
+    p[20..22].copy_from_slice(&1000u16.to_be_bytes());
+    p[22..24].copy_from_slice(&80u16.to_be_bytes());
+    p[24..28].copy_from_slice(&seq.to_be_bytes());
+    p[28..32].copy_from_slice(&ack.to_be_bytes());
+    p[32] = ((hdr_len / 4) as u8) << 4;
+    p[33] = flags;
+    p[34..36].copy_from_slice(&0xffffu16.to_be_bytes());
+    p[40..40 + opts.len()].copy_from_slice(opts);
+    p.extend_from_slice(payload);
+    p.into()
+}
+
+struct Segment {
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    wnd: u16,
+    opts: Vec<u8>,
+}
+
+fn parse(p: &[u8]) -> Segment {
+    let t = &p[((p[0] & 0xf) as usize) * 4..];
+    let doff = ((t[12] >> 4) as usize) * 4;
+    Segment {
+        flags: t[13],
+        seq: u32::from_be_bytes(t[4..8].try_into().unwrap()),
+        ack: u32::from_be_bytes(t[8..12].try_into().unwrap()),
+        wnd: u16::from_be_bytes([t[14], t[15]]),
+        opts: t[20..doff].to_vec(),
+    }
+}
+
+/// Returns the payload of the option `kind`.
+fn find_opt(opts: &[u8], kind: u8) -> Option<&[u8]> {
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            0 => return None,
+            1 => i += 1,
+            k => {
+                let len = opts[i + 1] as usize;
+                if k == kind {
+                    return Some(&opts[i + 2..i + len]);
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+// MSS 1460, window scale 7, SACK permitted.
+const SYN_OPTS: [u8; 12] = [2, 4, 0x05, 0xb4, 1, 3, 3, 7, 1, 1, 4, 2];
+
+/// Completes a handshake as the TUN side host, returns the stack's initial
+/// sequence number and the window scale it offered.
+async fn handshake<S, R>(sink: &mut S, stream: &mut R) -> (u32, Option<u8>)
+where
+    S: futures::Sink<Bytes> + Unpin,
+    S::Error: std::fmt::Debug,
+    R: futures::Stream<Item = std::io::Result<Bytes>> + Unpin,
+{
+    sink.send(tcp_pkt(PEER_ISS, 0, 0x02, &SYN_OPTS, b""))
+        .await
+        .unwrap();
+    let synack = parse(&stream.next().await.unwrap().unwrap());
+    assert_eq!(synack.flags, 0x12);
+    let scale = find_opt(&synack.opts, 3).map(|s| s[0]);
+    sink.send(tcp_pkt(PEER_ISS + 1, synack.seq + 1, 0x10, &[], b""))
+        .await
+        .unwrap();
+    (synack.seq, scale)
+}
+
+/// Sends as much as the advertised window allows for `duration`, returns
+/// the bytes the application read.
+async fn transfer(window_scaling: bool, duration: Duration) -> usize {
+    let (stack, mut listener, _udp) = netstack_lwip::NetStack::builder()
+        .window_scaling(window_scaling)
+        .timer_period(Duration::from_millis(10))
+        .build()
+        .unwrap();
+    let (mut sink, mut stream) = stack.split();
+    let (iss, scale) = handshake(&mut sink, &mut stream).await;
+    assert_eq!(scale.is_some(), window_scaling);
+    let shift = scale.unwrap_or(0);
+
+    let (mut tcp, _, _) = listener.next().await.unwrap();
+    let reader = tokio::spawn(async move {
+        let mut total = 0;
+        loop {
+            let data = tcp.as_mut().read_bytes().await.unwrap();
+            if data.is_empty() {
+                return total;
+            }
+            total += data.len();
+        }
+    });
+
+    // Packets from the stack reach the sender after a full round-trip.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(Ok(pkt)) = stream.next().await {
+            if tx.send((Instant::now() + RTT, pkt)).is_err() {
+                return;
+            }
+        }
+    });
+
+    let payload = vec![0u8; MSS];
+    let deadline = Instant::now() + duration;
+    let mut snd_una = PEER_ISS + 1;
+    let mut snd_nxt = snd_una;
+    let mut wnd = 0xffffusize;
+    while Instant::now() < deadline {
+        while (snd_nxt - snd_una) as usize + MSS <= wnd {
+            sink.send(tcp_pkt(snd_nxt, iss + 1, 0x10, &[], &payload))
+                .await
+                .unwrap();
+            snd_nxt += MSS as u32;
+        }
+        let (at, pkt) = match tokio::time::timeout_at(deadline.into(), rx.recv()).await {
+            Ok(Some(item)) => item,
+            _ => break,
+        };
+        tokio::time::sleep_until(at.into()).await;
+        let seg = parse(&pkt);
+        if seg.flags & 0x10 != 0 && seg.ack.wrapping_sub(snd_una) as i32 >= 0 {
+            snd_una = seg.ack;
+            wnd = (seg.wnd as usize) << shift;
+        }
+    }
+    sink.send(tcp_pkt(snd_nxt, iss + 1, 0x11, &[], b""))
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), reader)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[test]
+fn window_scaling_raises_throughput() {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let duration = Duration::from_secs(1);
+        let unscaled = transfer(false, duration).await;
+        let scaled = transfer(true, duration).await;
+        println!(
+            "{:?} RTT over {:?}: {} bytes unscaled, {} bytes scaled",
+            RTT, duration, unscaled, scaled
+        );
+        // At most 64KB per round-trip without scaling, 256KB with it.
+        assert!(unscaled <= 11 * 0xffff);
+        assert!(scaled > 2 * unscaled);
+    });
+}
+
+#[test]
+fn sack_reports_out_of_order_data() {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        for sack in [true, false] {
+            let (stack, _listener, _udp) = netstack_lwip::NetStack::builder()
+                .sack(sack)
+                .build()
+                .unwrap();
+            let (mut sink, mut stream) = stack.split();
+            let (iss, _) = handshake(&mut sink, &mut stream).await;
+            // The first segment is lost.
+            let seq = PEER_ISS + 1 + MSS as u32;
+            sink.send(tcp_pkt(seq, iss + 1, 0x10, &[], &[0u8; MSS]))
+                .await
+                .unwrap();
+            let dupack = parse(&stream.next().await.unwrap().unwrap());
+            assert_eq!(dupack.ack, PEER_ISS + 1);
+            let block = find_opt(&dupack.opts, 5);
+            assert_eq!(block.is_some(), sack);
+            if let Some(block) = block {
+                assert_eq!(u32::from_be_bytes(block[0..4].try_into().unwrap()), seq);
+                assert_eq!(
+                    u32::from_be_bytes(block[4..8].try_into().unwrap()),
+                    seq + MSS as u32
+                );
+            }
+        }
+    });
+}